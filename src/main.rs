@@ -1,26 +1,358 @@
 use core::f64;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::{self, BufRead, IsTerminal};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
 
 use color_eyre::Result;
 use ratatui::{
-    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
     layout::{Constraint, Layout, Position},
     style::{Color, Modifier, Style, Stylize},
+    symbols,
     text::{Line, Span, Text},
-    widgets::{Block, List, ListItem, Paragraph},
+    widgets::{
+        Axis, Bar, BarChart, BarGroup, Block, Chart, Dataset, GraphType, List, ListItem, Paragraph,
+    },
     DefaultTerminal, Frame,
 };
+use serde::{Deserialize, Serialize};
+use tinyjson::JsonValue;
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     color_eyre::install()?;
+    let mut app = App::new();
+    app.load_config();
+    // Piped input runs as a script instead of starting the TUI
+    if !io::stdin().is_terminal() {
+        return app.run_batch(io::stdin().lock());
+    }
     let terminal = ratatui::init();
-    let app_result = App::new().run(terminal);
+    let app_result = app.run(terminal);
     ratatui::restore();
-    app_result
+    app_result.map(|()| ExitCode::SUCCESS)
+}
+
+/// Directory under the OS config directory that the files below are kept in
+const CONFIG_DIR: &str = "rpn-calc";
+/// Saved stack and variables
+const STATE_FILE: &str = "state.json";
+/// Where older versions saved the stack, one value per line in the working directory. Read
+/// once when there is no `STATE_FILE` yet, so an upgrade keeps the stack.
+const LEGACY_STACK_FILE: &str = "stack.txt";
+const CONFIG_FILE: &str = "config.toml";
+const HISTORY_FILE: &str = "history.txt";
+const DEBUG_FILE: &str = "debug.toml";
+
+type UnaryOperation = fn(f64) -> f64;
+type BinaryOperation = fn(f64, f64) -> f64;
+
+/// Arithmetic operators, applied to the second value and the top value in that order
+const BINARY_OPERATORS: [(&str, BinaryOperation); 6] = [
+    ("+", |a, b| a + b),
+    ("-", |a, b| a - b),
+    ("*", |a, b| a * b),
+    ("/", |a, b| a / b),
+    ("%", |a, b| a % b),
+    ("^", |a, b| b.powf(a)),
+];
+
+/// Most values a single command may generate, so long-running commands can't freeze the UI
+const ITERATION_BUDGET: usize = 100_000;
+
+/// Relative difference below which two values are considered equal
+const FLOAT_TOLERANCE: f64 = 1e-9;
+
+/// Most times a count typed in Normal mode can repeat the next input
+const MAX_REPEAT: usize = 1000;
+
+/// Most submitted inputs kept in the history, and so in `HISTORY_FILE`
+const MAX_HISTORY: usize = 500;
+
+/// How long each step of an animated fold stays on screen
+const FOLD_FRAME_DELAY: Duration = Duration::from_millis(400);
+
+/// Most steps of a fold that are animated; the rest of the fold is skipped over
+const MAX_FOLD_FRAMES: usize = 25;
+
+/// Most stack states kept for undo unless the config says otherwise
+const DEFAULT_MAX_UNDO: usize = 100;
+
+/// Largest magnitude below which every whole number is exactly representable in an f64 (2^53)
+const MAX_EXACT_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Most decimals `fix` will display
+const MAX_PRECISION: usize = 20;
+
+/// Largest whole number whose factorial fits in an f64; anything above overflows to infinity
+const MAX_FACTORIAL: f64 = 170.0;
+
+/// Temperature conversions, keyed by the command that applies them to the top of the stack
+const TEMPERATURE_CONVERSIONS: [(&str, UnaryOperation); 4] = [
+    ("c2f", |c| c * 9.0 / 5.0 + 32.0),
+    ("f2c", |f| (f - 32.0) * 5.0 / 9.0),
+    ("c2k", |c| c + 273.15),
+    ("k2c", |k| k - 273.15),
+];
+
+/// Trigonometric functions in radians, with where their angle is
+const TRIG_FUNCTIONS: [(&str, TrigAngle, UnaryOperation); 9] = [
+    ("sin", TrigAngle::Input, |a| a.sin()),
+    ("cos", TrigAngle::Input, |a| a.cos()),
+    ("tan", TrigAngle::Input, |a| a.tan()),
+    ("asin", TrigAngle::Output, |a| a.asin()),
+    ("acos", TrigAngle::Output, |a| a.acos()),
+    ("atan", TrigAngle::Output, |a| a.atan()),
+    ("sindeg", TrigAngle::Degrees, |a| a.to_radians().sin()),
+    ("cosdeg", TrigAngle::Degrees, |a| a.to_radians().cos()),
+    ("tandeg", TrigAngle::Degrees, |a| a.to_radians().tan()),
+];
+
+/// Which side of a trig function takes the angle, and so follows the angle mode
+#[derive(Clone, Copy)]
+enum TrigAngle {
+    Input,
+    Output,
+    /// Always takes degrees, whatever the mode
+    Degrees,
+}
+
+/// Named constants that push their value, beyond the dedicated `inf` and `pi`
+const CONSTANTS: [(&str, f64); 6] = [
+    ("e", f64::consts::E),
+    ("tau", f64::consts::TAU),
+    ("phi", 1.618_033_988_749_895),
+    ("sqrt2", f64::consts::SQRT_2),
+    ("sqrt3", 1.732_050_807_568_877_2),
+    ("ln2", f64::consts::LN_2),
+];
+
+/// How many stack values a command consumes
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Arity {
+    /// Exactly this many values
+    Fixed(usize),
+    /// A count n from the top of the stack, plus however many values n calls for
+    Counted,
+    /// Every value on the stack
+    All,
 }
 
-const STACK_FILE: &str = "stack.txt";
+/// Commands shown in the operations guide, grouped by category. The temperature conversions
+/// and named constants are added to their groups from their own tables.
+const OPERATIONS: &[(&str, &[(&str, Arity)])] = &[
+    (
+        "Binary",
+        &[
+            ("+", Arity::Fixed(2)),
+            ("-", Arity::Fixed(2)),
+            ("*", Arity::Fixed(2)),
+            ("/", Arity::Fixed(2)),
+            ("%", Arity::Fixed(2)),
+            ("^", Arity::Fixed(2)),
+            ("sigfig", Arity::Fixed(2)),
+            ("atan2", Arity::Fixed(2)),
+            ("pcterr", Arity::Fixed(2)),
+        ],
+    ),
+    (
+        "Unary",
+        &[
+            ("neg", Arity::Fixed(1)),
+            ("abs", Arity::Fixed(1)),
+            ("sqrt", Arity::Fixed(1)),
+            ("sin", Arity::Fixed(1)),
+            ("cos", Arity::Fixed(1)),
+            ("tan", Arity::Fixed(1)),
+            ("asin", Arity::Fixed(1)),
+            ("acos", Arity::Fixed(1)),
+            ("atan", Arity::Fixed(1)),
+            ("sindeg", Arity::Fixed(1)),
+            ("cosdeg", Arity::Fixed(1)),
+            ("tandeg", Arity::Fixed(1)),
+            ("deg", Arity::Fixed(1)),
+            ("rad", Arity::Fixed(1)),
+            ("!", Arity::Fixed(1)),
+            ("recip", Arity::Fixed(1)),
+            ("floor", Arity::Fixed(1)),
+            ("ceil", Arity::Fixed(1)),
+            ("round", Arity::Fixed(1)),
+            ("trunc", Arity::Fixed(1)),
+            ("toint", Arity::Fixed(1)),
+            ("isint", Arity::Fixed(1)),
+            ("log10", Arity::Fixed(1)),
+            ("logn", Arity::Fixed(1)),
+            ("log2", Arity::Fixed(1)),
+            ("exp", Arity::Fixed(1)),
+            ("exp10", Arity::Fixed(1)),
+            ("alog", Arity::Fixed(1)),
+        ],
+    ),
+    (
+        "Vector",
+        &[
+            ("vadd", Arity::Counted),
+            ("vmul", Arity::Counted),
+            ("cross", Arity::Fixed(6)),
+            ("norm", Arity::Counted),
+        ],
+    ),
+    ("Conversions", &[("convert <from> <to>", Arity::Fixed(1))]),
+    (
+        "Finance",
+        &[
+            ("discount", Arity::Fixed(2)),
+            ("markup", Arity::Fixed(2)),
+            ("compound", Arity::Fixed(3)),
+            ("cents", Arity::Fixed(1)),
+        ],
+    ),
+    (
+        "Stack",
+        &[
+            ("swap", Arity::Fixed(2)),
+            ("swap2", Arity::Fixed(4)),
+            ("rot", Arity::Fixed(3)),
+            ("over", Arity::Fixed(2)),
+            ("rolln", Arity::Counted),
+            ("swapat", Arity::Counted),
+            ("pick", Arity::Counted),
+            ("roll", Arity::Counted),
+            ("clear", Arity::Fixed(0)),
+            ("drop", Arity::Fixed(1)),
+            ("keeptop", Arity::Fixed(1)),
+            ("clone (empty)", Arity::Fixed(1)),
+            ("dupbottom", Arity::Fixed(1)),
+            ("range", Arity::Fixed(2)),
+            ("dedup", Arity::All),
+            ("runlength", Arity::All),
+            ("reduce <op>", Arity::All),
+            ("set <values>", Arity::All),
+            ("dupapply <op>", Arity::Fixed(1)),
+            ("over2 <op>", Arity::Fixed(2)),
+            ("decimals", Arity::Fixed(1)),
+            ("pin", Arity::Fixed(1)),
+            ("unpin", Arity::Fixed(0)),
+        ],
+    ),
+    (
+        "History",
+        &[
+            ("undo", Arity::Fixed(0)),
+            ("redo", Arity::Fixed(0)),
+            ("branch", Arity::Fixed(0)),
+            ("noundo", Arity::Fixed(0)),
+            ("undoon", Arity::Fixed(0)),
+        ],
+    ),
+    (
+        "Display",
+        &[
+            ("summary", Arity::Fixed(0)),
+            ("result", Arity::Fixed(0)),
+            ("index", Arity::Fixed(0)),
+            ("debugmode", Arity::Fixed(0)),
+            ("debug", Arity::Fixed(0)),
+            ("bits", Arity::Fixed(0)),
+            ("hist", Arity::Fixed(0)),
+            ("plot", Arity::Fixed(0)),
+            ("spark", Arity::Fixed(0)),
+            ("confirm", Arity::Fixed(0)),
+            ("fix <n>", Arity::Fixed(0)),
+            ("mode <deg|rad>", Arity::Fixed(0)),
+            ("std", Arity::Fixed(0)),
+            ("zeros", Arity::Fixed(0)),
+            ("animate", Arity::Fixed(0)),
+            ("saveconfig", Arity::Fixed(0)),
+            ("infix", Arity::Fixed(0)),
+        ],
+    ),
+    (
+        "Data",
+        &[
+            ("countgt", Arity::All),
+            ("countlt", Arity::All),
+            ("counteq", Arity::All),
+            ("filtergt", Arity::All),
+            ("argmax", Arity::All),
+            ("lerp", Arity::Fixed(3)),
+            ("normalizemax", Arity::All),
+            ("harmean", Arity::All),
+            ("sum", Arity::All),
+            ("prod", Arity::All),
+            ("mean", Arity::All),
+            ("stddev", Arity::All),
+        ],
+    ),
+    (
+        "Variables",
+        &[
+            ("sto <name>", Arity::Fixed(1)),
+            ("rcl <name>", Arity::Fixed(0)),
+        ],
+    ),
+    (
+        "Number theory",
+        &[
+            ("cfrac", Arity::Fixed(2)),
+            ("modpow", Arity::Fixed(3)),
+            ("modinv", Arity::Fixed(2)),
+            ("gcd", Arity::Fixed(2)),
+            ("lcm", Arity::Fixed(2)),
+        ],
+    ),
+    (
+        "Bitwise",
+        &[
+            ("and", Arity::Fixed(2)),
+            ("or", Arity::Fixed(2)),
+            ("xor", Arity::Fixed(2)),
+            ("shl", Arity::Fixed(2)),
+            ("shr", Arity::Fixed(2)),
+        ],
+    ),
+    (
+        "Clipboard",
+        &[("copyprec", Arity::Fixed(2)), ("copyeng", Arity::Fixed(1))],
+    ),
+    (
+        "Constants",
+        &[("inf", Arity::Fixed(0)), ("pi", Arity::Fixed(0))],
+    ),
+    (
+        "Program",
+        &[
+            ("baseline", Arity::Fixed(0)),
+            ("checkbaseline", Arity::Fixed(0)),
+            ("assert", Arity::Fixed(2)),
+            ("print", Arity::Fixed(1)),
+            ("eval \"<rpn>\"", Arity::Fixed(0)),
+            ("quit", Arity::Fixed(0)),
+        ],
+    ),
+];
+
+/// Units understood by `convert`, as (name, quantity, size in the quantity's base unit)
+const UNITS: [(&str, &str, f64); 15] = [
+    ("m", "length", 1.0),
+    ("cm", "length", 0.01),
+    ("mm", "length", 0.001),
+    ("km", "length", 1000.0),
+    ("in", "length", 0.0254),
+    ("ft", "length", 0.3048),
+    ("yd", "length", 0.9144),
+    ("mi", "length", 1609.344),
+    ("kg", "mass", 1.0),
+    ("g", "mass", 0.001),
+    ("lb", "mass", 0.453_592_37),
+    ("oz", "mass", 0.028_349_523_125),
+    ("l", "volume", 1.0),
+    ("ml", "volume", 0.001),
+    ("gal", "volume", 3.785_411_784),
+];
 
 /// App holds the state of the application
 struct App {
@@ -30,41 +362,793 @@ struct App {
     character_index: usize,
     /// Current input mode
     input_mode: InputMode,
+    /// Mode the app starts in, kept so `saveconfig` doesn't save whatever mode is current
+    start_mode: InputMode,
+    /// Unit the trig functions measure angles in
+    angle_mode: AngleMode,
     /// History of recorded messages
     stack: Vec<f64>,
-    undo: Vec<Vec<f64>>,
-    redo: Vec<Vec<f64>>,
+    /// Every earlier stack, including ones undone and then branched away from
+    undo: UndoTree,
+    /// Message explaining why the last input could not be applied
+    error: Option<String>,
+    /// Informational message about the last input
+    status: Option<String>,
+    /// Where printed values go
+    output: Output,
+    /// Whether to show the top few stack values on a single line above the help
+    show_summary: bool,
+    /// Whether to show the top of the stack prominently below the input
+    show_result: bool,
+    /// Whether stack entries are prefixed with their distance from the top
+    show_indices: bool,
+    /// Whether values are shown exactly as stored, e.g. keeping the sign of -0
+    debug_mode: bool,
+    /// Whether to show the raw IEEE 754 fields of the top value below the input
+    show_bits: bool,
+    /// Whether to chart the stack as bars below the input
+    show_histogram: bool,
+    /// Whether to chart the stack as a line below the input
+    show_plot: bool,
+    /// Whether the status line shows a sparkline of the stack when it has nothing else to say
+    show_sparkline: bool,
+    /// Whether destructive commands wait for `y` before running
+    confirm_destructive: bool,
+    /// Destructive command waiting for confirmation
+    pending: Option<String>,
+    /// Number of entries scrolled past at the top of the stack panel
+    stack_offset: usize,
+    /// Rows available to the stack panel when it was last drawn, used as the page size
+    stack_page_size: Cell<usize>,
+    /// Positions, counted from the bottom, that the last input changed
+    changed: Vec<usize>,
+    /// Count typed in Normal mode, applied to the next submitted input
+    repeat_count: Option<usize>,
+    /// Number of decimals to display, or `None` for the shortest exact representation
+    precision: Option<usize>,
+    /// Whether fixed-precision values keep their trailing zeros
+    trailing_zeros: bool,
+    /// Whether mutating commands snapshot the stack for undo
+    record_undo: bool,
+    /// The stack when `noundo` turned recording off, to tell on `undoon` if it changed since
+    paused_stack: Option<Vec<f64>>,
+    /// Most stack states kept for undo, dropping the oldest beyond that
+    max_undo: usize,
+    /// Whether `reduce` shows each step of the fold before the result
+    animate_folds: bool,
+    /// Intermediate stacks from the last animated fold, waiting to be drawn
+    fold_frames: Vec<Vec<f64>>,
+    /// Stack saved by `baseline` for `checkbaseline` to compare against
+    baseline: Option<Vec<f64>>,
+    /// Infix form of each stack value, as far as the inputs that built it can be followed
+    expressions: Vec<Expression>,
+    /// Formulas defined with `name = body`, by name
+    formulas: HashMap<String, Formula>,
+    /// Values stored with `sto name`, by name
+    variables: HashMap<String, f64>,
+    /// Reference values moved off the stack with `pin`, oldest first
+    pinned: Vec<f64>,
+    /// Previously submitted inputs, oldest first
+    history: Vec<String>,
+    /// Entry of `history` shown in the input while recalling with Up/Down
+    history_cursor: Option<usize>,
+    /// What was being typed before recalling, restored by moving Down past the newest entry
+    history_draft: String,
+    /// System clipboard, opened on first copy and kept alive so the copied text stays available
+    clipboard: Option<arboard::Clipboard>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 enum InputMode {
     Normal,
     Editing,
 }
 
+/// Unit that `sin`, `asin` and the other trig functions measure angles in
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum AngleMode {
+    Radians,
+    Degrees,
+}
+
+impl AngleMode {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Radians => "radians",
+            Self::Degrees => "degrees",
+        }
+    }
+
+    fn to_radians(self, angle: f64) -> f64 {
+        match self {
+            Self::Radians => angle,
+            Self::Degrees => angle.to_radians(),
+        }
+    }
+
+    fn convert_from_radians(self, angle: f64) -> f64 {
+        match self {
+            Self::Radians => angle,
+            Self::Degrees => angle.to_degrees(),
+        }
+    }
+}
+
+/// Sparkline blocks from lowest to highest
+const SPARK_LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Height of the tallest bar in the histogram panel, which the others are scaled against
+const HISTOGRAM_SCALE: u64 = 100;
+
+/// Stack states that can be undone to, as a tree: a change made after undoing starts a new
+/// branch rather than discarding the undone states, and `redo` follows the newest branch.
+///
+/// States aren't stored whole. Each node keeps the `StackDelta` from its parent, and `undo`
+/// and `redo` apply those to the live stack, so a step costs only the values it touched.
+/// Anything else a step changed, such as the pinned values, is kept with it as `SideChange`s.
+#[derive(Clone, Default)]
+struct UndoTree {
+    nodes: Vec<UndoNode>,
+    /// Node for the live stack, or for the state it was changed from while `unsaved` is set
+    head: Option<usize>,
+    /// A change made since `head`, as the values kept below it and the values it replaced. Its
+    /// node is only added once the change is complete, when the next one starts or it is undone.
+    unsaved: Option<(usize, Vec<f64>)>,
+    /// Changes outside the stack made by the unsaved change, in order
+    unsaved_side: Vec<SideChange>,
+}
+
+#[derive(Clone)]
+struct UndoNode {
+    /// How to get here from the parent; `None` for the oldest states, which can't be undone
+    delta: Option<StackDelta>,
+    /// Changes outside the stack made on the way here, in order
+    side: Vec<SideChange>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// Child that `redo` moves to
+    redo: Option<usize>,
+}
+
+/// A change to the top of the stack: the first `keep` values were left alone and the
+/// `removed` values above them were replaced by `added`.
+#[derive(Clone, Debug, PartialEq)]
+struct StackDelta {
+    keep: usize,
+    removed: Vec<f64>,
+    added: Vec<f64>,
+}
+
+impl StackDelta {
+    fn undo(&self, stack: &mut Vec<f64>) {
+        stack.truncate(self.keep);
+        stack.extend_from_slice(&self.removed);
+    }
+
+    fn redo(&self, stack: &mut Vec<f64>) {
+        stack.truncate(self.keep);
+        stack.extend_from_slice(&self.added);
+    }
+
+    /// The single change that has the effect of `self` followed by `next`.
+    fn then(&self, next: &StackDelta) -> StackDelta {
+        if next.keep <= self.keep {
+            // `next` reaches below `self`, so it removed some values `self` left alone
+            let mut removed = next.removed[..self.keep - next.keep].to_vec();
+            removed.extend_from_slice(&self.removed);
+            StackDelta {
+                keep: next.keep,
+                removed,
+                added: next.added.clone(),
+            }
+        } else {
+            let mut added = self.added[..next.keep - self.keep].to_vec();
+            added.extend_from_slice(&next.added);
+            StackDelta {
+                keep: self.keep,
+                removed: self.removed.clone(),
+                added,
+            }
+        }
+    }
+}
+
+/// A change outside the stack that belongs to an undo step, such as pinning a value
+#[derive(Clone, Debug, PartialEq)]
+enum SideChange {
+    /// The pinned values before and after
+    Pinned(Vec<f64>, Vec<f64>),
+    /// A variable's value before and after, `None` where it wasn't set
+    Variable(String, Option<f64>, Option<f64>),
+}
+
+impl SideChange {
+    fn undo(&self, pinned: &mut Vec<f64>, variables: &mut HashMap<String, f64>) {
+        match self {
+            Self::Pinned(before, _) => pinned.clone_from(before),
+            Self::Variable(name, before, _) => set_variable(variables, name, *before),
+        }
+    }
+
+    fn redo(&self, pinned: &mut Vec<f64>, variables: &mut HashMap<String, f64>) {
+        match self {
+            Self::Pinned(_, after) => pinned.clone_from(after),
+            Self::Variable(name, _, after) => set_variable(variables, name, *after),
+        }
+    }
+}
+
+fn set_variable(variables: &mut HashMap<String, f64>, name: &str, value: Option<f64>) {
+    match value {
+        Some(value) => variables.insert(name.to_string(), value),
+        None => variables.remove(name),
+    };
+}
+
+impl UndoTree {
+    /// Saves what a change to `live` is about to replace: everything above its bottom
+    /// `live.len() - depth` values, which it leaves alone.
+    fn record(&mut self, live: &[f64], depth: usize) {
+        if self.unsaved.is_some() {
+            self.save_unsaved(live);
+        } else if self.head.is_none() {
+            self.head = Some(self.add(None, Vec::new(), None));
+        }
+        let keep = live.len().saturating_sub(depth);
+        self.unsaved = Some((keep, live[keep..].to_vec()));
+    }
+
+    /// Adds a change outside the stack to the change just recorded.
+    fn note(&mut self, change: SideChange) {
+        if self.unsaved.is_some() {
+            self.unsaved_side.push(change);
+        }
+    }
+
+    /// Adds the change in `unsaved` as a child of `head`, which becomes its node.
+    ///
+    /// Changes that had to save the whole stack, not knowing how much of it they would touch,
+    /// are trimmed here to the values that actually changed.
+    fn save_unsaved(&mut self, live: &[f64]) -> Option<usize> {
+        let (keep, mut removed) = self.unsaved.take()?;
+        let mut added = live[keep.min(live.len())..].to_vec();
+        let unchanged = removed
+            .iter()
+            .zip(&added)
+            .take_while(|(before, after)| before.to_bits() == after.to_bits())
+            .count();
+        removed.drain(..unchanged);
+        added.drain(..unchanged);
+        let delta = StackDelta {
+            keep: keep + unchanged,
+            removed,
+            added,
+        };
+        let side = std::mem::take(&mut self.unsaved_side);
+        let node = self.add(Some(delta), side, self.head);
+        self.head = Some(node);
+        Some(node)
+    }
+
+    /// Steps `live` back to the previous state, returning the changes outside the stack that
+    /// the step made, or `None` if there is no previous state.
+    fn undo(&mut self, live: &mut Vec<f64>) -> Option<&[SideChange]> {
+        // Keep an unsaved change so it can be redone
+        self.save_unsaved(live);
+        let head = self.head?;
+        let (Some(delta), Some(parent)) = (&self.nodes[head].delta, self.nodes[head].parent) else {
+            return None;
+        };
+        delta.undo(live);
+        self.nodes[parent].redo = Some(head);
+        self.head = Some(parent);
+        Some(&self.nodes[head].side)
+    }
+
+    fn redo(&mut self, live: &mut Vec<f64>) -> Option<&[SideChange]> {
+        if self.unsaved.is_some() {
+            return None;
+        }
+        let child = self.head.and_then(|head| self.nodes[head].redo)?;
+        if let Some(delta) = &self.nodes[child].delta {
+            delta.redo(live);
+        }
+        self.head = Some(child);
+        Some(&self.nodes[child].side)
+    }
+
+    /// Moves `redo` at the current state on to its next branch, returning its position among
+    /// the branches and how many there are.
+    fn next_branch(&mut self) -> Option<(usize, usize)> {
+        if self.unsaved.is_some() {
+            return None;
+        }
+        let node = &mut self.nodes[self.head?];
+        if node.children.len() < 2 {
+            return None;
+        }
+        let current = node
+            .redo
+            .and_then(|redo| node.children.iter().position(|child| *child == redo))
+            .unwrap_or(0);
+        let next = (current + 1) % node.children.len();
+        node.redo = Some(node.children[next]);
+        Some((next + 1, node.children.len()))
+    }
+
+    /// Drops the oldest states, other than the one at `head`, until at most `limit` are left.
+    fn prune(&mut self, limit: usize) {
+        while self.nodes.len() > limit {
+            let oldest = usize::from(self.head == Some(0));
+            if oldest >= self.nodes.len() {
+                return;
+            }
+            self.remove(oldest);
+        }
+    }
+
+    /// Removes a node, handing its children to its parent with the deltas joined up.
+    fn remove(&mut self, node: usize) {
+        let UndoNode {
+            delta,
+            side,
+            parent,
+            children,
+            ..
+        } = self.nodes.remove(node);
+        let shift = |id: usize| if id > node { id - 1 } else { id };
+        if let Some(parent) = parent {
+            let parent = &mut self.nodes[shift(parent)];
+            let position = parent.children.iter().position(|child| *child == node);
+            if let Some(position) = position {
+                parent
+                    .children
+                    .splice(position..=position, children.iter().copied());
+            }
+            if parent.redo == Some(node) {
+                parent.redo = children.last().copied();
+            }
+        }
+        for &child in &children {
+            let child = &mut self.nodes[shift(child)];
+            child.parent = parent;
+            child.delta = match (&delta, &child.delta) {
+                (Some(first), Some(second)) => {
+                    child.side.splice(..0, side.iter().cloned());
+                    Some(first.then(second))
+                }
+                _ => {
+                    child.side.clear();
+                    None
+                }
+            };
+        }
+        for entry in &mut self.nodes {
+            entry.parent = entry.parent.map(shift);
+            entry.redo = entry.redo.map(shift);
+            for child in &mut entry.children {
+                *child = shift(*child);
+            }
+        }
+        self.head = self.head.map(shift);
+    }
+
+    /// How many times `undo` can step back from the live stack.
+    fn undo_depth(&self) -> usize {
+        let saved = std::iter::successors(self.head, |node| self.nodes[*node].parent)
+            .filter(|node| self.nodes[*node].delta.is_some())
+            .count();
+        saved + usize::from(self.unsaved.is_some())
+    }
+
+    fn redo_depth(&self) -> usize {
+        if self.unsaved.is_some() {
+            return 0;
+        }
+        std::iter::successors(self.head, |node| self.nodes[*node].redo)
+            .count()
+            .saturating_sub(1)
+    }
+
+    fn add(
+        &mut self,
+        delta: Option<StackDelta>,
+        side: Vec<SideChange>,
+        parent: Option<usize>,
+    ) -> usize {
+        let node = self.nodes.len();
+        self.nodes.push(UndoNode {
+            delta,
+            side,
+            parent,
+            children: Vec::new(),
+            redo: None,
+        });
+        if let Some(parent) = parent {
+            self.nodes[parent].children.push(node);
+        }
+        node
+    }
+}
+
+/// The stack and variables as saved between sessions, as a JSON object
+#[derive(Debug, Default, PartialEq)]
+struct SavedState {
+    stack: Vec<f64>,
+    variables: HashMap<String, f64>,
+}
+
+impl SavedState {
+    fn to_json(&self) -> JsonValue {
+        let stack = self
+            .stack
+            .iter()
+            .map(|value| stored_value(*value))
+            .collect();
+        let variables = self
+            .variables
+            .iter()
+            .map(|(name, value)| (name.clone(), stored_value(*value)))
+            .collect();
+        JsonValue::Object(HashMap::from([
+            (String::from("stack"), JsonValue::Array(stack)),
+            (String::from("variables"), JsonValue::Object(variables)),
+        ]))
+    }
+
+    /// Reads back a state written by `to_json`, or `None` if `json` isn't one. The variables
+    /// may be left out.
+    fn from_json(json: &JsonValue) -> Option<Self> {
+        let JsonValue::Object(fields) = json else {
+            return None;
+        };
+        let JsonValue::Array(stack) = fields.get("stack")? else {
+            return None;
+        };
+        let variables = match fields.get("variables") {
+            None => HashMap::new(),
+            Some(JsonValue::Object(variables)) => variables
+                .iter()
+                .map(|(name, value)| Some((name.clone(), read_stored_value(value)?)))
+                .collect::<Option<_>>()?,
+            Some(_) => return None,
+        };
+        Some(Self {
+            stack: stack.iter().map(read_stored_value).collect::<Option<_>>()?,
+            variables,
+        })
+    }
+}
+
+/// Where `file` is kept: under `CONFIG_DIR` in the OS config directory, or in the working
+/// directory when the OS has none.
+fn config_path(file: &str) -> PathBuf {
+    dirs::config_dir()
+        .map(|dir| dir.join(CONFIG_DIR).join(file))
+        .unwrap_or_else(|| PathBuf::from(file))
+}
+
+/// Writes `text` to `path`, creating its directory first if need be.
+fn write_config_file(path: &Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, text)?;
+    Ok(())
+}
+
+/// A value as saved to JSON, which can't write infinity or NaN as numbers, so those are kept
+/// as text instead.
+fn stored_value(value: f64) -> JsonValue {
+    if value.is_finite() {
+        JsonValue::Number(value)
+    } else {
+        JsonValue::String(value.to_string())
+    }
+}
+
+fn read_stored_value(json: &JsonValue) -> Option<f64> {
+    match json {
+        JsonValue::Number(number) => Some(*number),
+        JsonValue::String(text) => Some(text.parse().unwrap_or(f64::NAN)),
+        _ => None,
+    }
+}
+
+/// Infix text for a stack value, with the precedence of its outermost operator
+type Expression = (String, u8);
+
+/// Precedence of numbers, constants and function calls, which never need brackets
+const ATOM_PRECEDENCE: u8 = 4;
+
+/// A named sequence of commands, applied like a built-in operation
+#[derive(Clone)]
+struct Formula {
+    body: String,
+    /// How many stack values the body needs
+    arity: usize,
+}
+
+/// Startup settings read from `CONFIG_FILE`; anything left out keeps its default
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    /// Decimals to display, or none for the shortest exact representation
+    precision: Option<usize>,
+    trailing_zeros: bool,
+    summary: bool,
+    result: bool,
+    indices: bool,
+    /// Whether destructive commands wait for a confirming keystroke
+    confirm: bool,
+    /// Whether to start in Normal mode or straight in Editing mode
+    start_mode: InputMode,
+    angle_mode: AngleMode,
+    /// Most stack states kept for undo
+    max_undo: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            precision: None,
+            trailing_zeros: true,
+            summary: false,
+            result: false,
+            indices: true,
+            confirm: false,
+            start_mode: InputMode::Editing,
+            angle_mode: AngleMode::Radians,
+            max_undo: DEFAULT_MAX_UNDO,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the settings saved at `path`, or the defaults if there is no file there.
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+/// Snapshot of the engine written by `debug`, for attaching to bug reports
+#[derive(Debug, Serialize)]
+struct DebugState {
+    stack: Vec<f64>,
+    undo_depth: usize,
+    redo_depth: usize,
+    input_mode: InputMode,
+    record_undo: bool,
+    debug_mode: bool,
+    animate_folds: bool,
+    pending: Option<String>,
+    formulas: Vec<String>,
+    settings: Config,
+}
+
+/// Where `print` sends its output
+enum Output {
+    /// The status line of the TUI
+    Status,
+    /// Standard output, used in batch mode
+    Stdout,
+}
+
 impl App {
     fn new() -> Self {
         Self {
             input: String::new(),
             input_mode: InputMode::Editing,
-            stack: App::load_stack().unwrap_or_default(),
-            undo: Vec::new(),
-            redo: Vec::new(),
+            start_mode: InputMode::Editing,
+            angle_mode: AngleMode::Radians,
+            stack: Vec::new(),
+            undo: UndoTree::default(),
+            error: None,
+            status: None,
+            output: Output::Status,
+            show_summary: false,
+            show_result: false,
+            show_indices: true,
+            debug_mode: false,
+            show_bits: false,
+            show_histogram: false,
+            show_plot: false,
+            show_sparkline: false,
+            confirm_destructive: false,
+            pending: None,
+            stack_offset: 0,
+            stack_page_size: Cell::new(1),
+            changed: Vec::new(),
+            repeat_count: None,
+            precision: None,
+            trailing_zeros: true,
+            record_undo: true,
+            paused_stack: None,
+            max_undo: DEFAULT_MAX_UNDO,
+            animate_folds: false,
+            fold_frames: Vec::new(),
+            baseline: None,
+            expressions: Vec::new(),
+            formulas: HashMap::new(),
+            variables: HashMap::new(),
+            pinned: Vec::new(),
+            history: Vec::new(),
+            history_cursor: None,
+            history_draft: String::new(),
+            clipboard: None,
             character_index: 0,
         }
     }
 
-    fn load_stack() -> Result<Vec<f64>> {
-        if !std::path::Path::new(STACK_FILE).exists() {
+    fn load_config(&mut self) {
+        self.load_config_from(&config_path(CONFIG_FILE));
+    }
+
+    /// Applies the settings saved at `path`. A file that can't be read or parsed, say because
+    /// of a mistyped key, is reported in the status line and the defaults are kept.
+    fn load_config_from(&mut self, path: &Path) {
+        match Config::load(path) {
+            Ok(config) => self.apply_config(config),
+            Err(err) => {
+                // TOML errors quote the offending lines, too much for the status line
+                let reason = match err.downcast_ref::<toml::de::Error>() {
+                    Some(err) => err.message().to_string(),
+                    None => err.to_string(),
+                };
+                self.status = Some(format!("ignoring {}: {reason}", path.display()));
+            }
+        }
+    }
+
+    fn apply_config(&mut self, config: Config) {
+        self.precision = config
+            .precision
+            .filter(|precision| *precision <= MAX_PRECISION);
+        self.trailing_zeros = config.trailing_zeros;
+        self.show_summary = config.summary;
+        self.show_result = config.result;
+        self.show_indices = config.indices;
+        self.confirm_destructive = config.confirm;
+        self.start_mode = config.start_mode;
+        self.input_mode = config.start_mode;
+        self.angle_mode = config.angle_mode;
+        self.max_undo = config.max_undo;
+    }
+
+    /// The current settings, in the form they are saved to `CONFIG_FILE`.
+    fn config(&self) -> Config {
+        Config {
+            precision: self.precision,
+            trailing_zeros: self.trailing_zeros,
+            summary: self.show_summary,
+            result: self.show_result,
+            indices: self.show_indices,
+            confirm: self.confirm_destructive,
+            start_mode: self.start_mode,
+            angle_mode: self.angle_mode,
+            max_undo: self.max_undo,
+        }
+    }
+
+    fn save_config(&mut self) {
+        let path = config_path(CONFIG_FILE);
+        let written = self
+            .config()
+            .to_toml()
+            .and_then(|text| write_config_file(&path, &text));
+        match written {
+            Ok(()) => self.status = Some(format!("settings saved to {}", path.display())),
+            Err(err) => self.error = Some(format!("could not save settings: {err}")),
+        }
+    }
+
+    fn debug_state(&self) -> DebugState {
+        let mut formulas: Vec<String> = self.formulas.keys().cloned().collect();
+        formulas.sort();
+        DebugState {
+            stack: self.stack.clone(),
+            undo_depth: self.undo.undo_depth(),
+            redo_depth: self.undo.redo_depth(),
+            input_mode: self.input_mode,
+            record_undo: self.record_undo,
+            debug_mode: self.debug_mode,
+            animate_folds: self.animate_folds,
+            pending: self.pending.clone(),
+            formulas,
+            settings: self.config(),
+        }
+    }
+
+    fn dump_debug_state(&mut self) {
+        let path = config_path(DEBUG_FILE);
+        let written = toml::to_string(&self.debug_state())
+            .map_err(color_eyre::Report::from)
+            .and_then(|text| write_config_file(&path, &text));
+        match written {
+            Ok(()) => self.status = Some(format!("state written to {}", path.display())),
+            Err(err) => self.error = Some(format!("could not write state: {err}")),
+        }
+    }
+
+    fn load_state(&mut self) {
+        self.load_state_or_legacy(&config_path(STATE_FILE), Path::new(LEGACY_STACK_FILE));
+    }
+
+    /// Restores the state saved at `path`, or if there is none, the stack from a `legacy`
+    /// stack file.
+    fn load_state_or_legacy(&mut self, path: &Path, legacy: &Path) {
+        if path.exists() || !legacy.exists() {
+            self.load_state_from(path);
+            return;
+        }
+        self.stack = std::fs::read_to_string(legacy)
+            .map(|text| {
+                text.lines()
+                    .filter_map(|line| line.parse::<f64>().ok())
+                    .collect()
+            })
+            .unwrap_or_default();
+        self.variables.clear();
+    }
+
+    fn save_state(&self) -> Result<()> {
+        self.save_state_to(&config_path(STATE_FILE))
+    }
+
+    /// Restores the stack and variables saved at `path`. A missing or unreadable file leaves
+    /// both empty.
+    fn load_state_from(&mut self, path: &Path) {
+        let state = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|text| text.parse::<JsonValue>().ok())
+            .and_then(|json| SavedState::from_json(&json))
+            .unwrap_or_default();
+        self.stack = state.stack;
+        self.variables = state.variables;
+    }
+
+    fn save_state_to(&self, path: &Path) -> Result<()> {
+        let state = SavedState {
+            stack: self.stack.clone(),
+            variables: self.variables.clone(),
+        };
+        write_config_file(path, &state.to_json().format()?)
+    }
+
+    fn load_history() -> Result<Vec<String>> {
+        let path = config_path(HISTORY_FILE);
+        if !path.exists() {
             return Ok(Vec::new());
         }
-        let file = File::open(STACK_FILE)?;
-        let reader = BufReader::new(file);
-        let stack = reader
-            .lines()
-            .map_while(Result::ok)
-            .filter_map(|line| line.parse::<f64>().ok())
-            .collect();
-        Ok(stack)
+        Ok(parse_history(&std::fs::read_to_string(path)?))
+    }
+
+    fn save_history(&self) -> Result<()> {
+        write_config_file(&config_path(HISTORY_FILE), &format_history(&self.history))
+    }
+
+    /// Remembers a submitted input, skipping blanks and immediate repeats.
+    fn record_history(&mut self, input: &str) {
+        if input.is_empty() || self.history.last().is_some_and(|last| last == input) {
+            return;
+        }
+        self.history.push(input.to_string());
+        if self.history.len() > MAX_HISTORY {
+            self.history.drain(..self.history.len() - MAX_HISTORY);
+        }
     }
 
     fn move_cursor_left(&mut self) {
@@ -117,6 +1201,71 @@ impl App {
         }
     }
 
+    /// Adds or removes a leading minus sign, like the +/- key on a physical calculator.
+    fn toggle_input_sign(&mut self) {
+        if let Some(unsigned) = self.input.strip_prefix('-') {
+            self.input = unsigned.to_string();
+            self.move_cursor_left();
+        } else {
+            self.input.insert(0, '-');
+            self.move_cursor_right();
+        }
+    }
+
+    /// Types the digits of a constant into the input at the cursor.
+    fn insert_constant(&mut self, value: f64) {
+        for digit in value.to_string().chars() {
+            self.enter_char(digit);
+        }
+    }
+
+    /// Replaces the input with the previous entry of `history`, keeping what was typed so far.
+    fn recall_previous(&mut self) {
+        let index = match self.history_cursor {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.history_draft = self.input.clone();
+                self.history.len() - 1
+            }
+        };
+        self.history_cursor = Some(index);
+        self.set_input(self.history[index].clone());
+    }
+
+    /// Replaces the input with the next entry of `history`, or with what was being typed
+    /// once past the newest.
+    fn recall_next(&mut self) {
+        let Some(index) = self.history_cursor else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.history_cursor = Some(index + 1);
+            self.set_input(self.history[index + 1].clone());
+        } else {
+            self.history_cursor = None;
+            let draft = std::mem::take(&mut self.history_draft);
+            self.set_input(draft);
+        }
+    }
+
+    /// Replaces the input, leaving the cursor at its end.
+    fn set_input(&mut self, input: String) {
+        self.input = input;
+        self.character_index = self.input.chars().count();
+    }
+
+    /// Clears the whole input if it is exactly a known command, otherwise deletes one character.
+    fn delete_command_or_char(&mut self) {
+        if is_known_command(&self.input) {
+            self.input.clear();
+            self.reset_cursor();
+        } else {
+            self.delete_char();
+        }
+    }
+
     fn clamp_cursor(&self, new_cursor_pos: usize) -> usize {
         new_cursor_pos.clamp(0, self.input.chars().count())
     }
@@ -125,84 +1274,456 @@ impl App {
         self.character_index = 0;
     }
 
-    fn save_stack(&self) -> Result<()> {
-        let mut file = File::create(STACK_FILE)?;
-        for num in &self.stack {
-            writeln!(file, "{num}")?;
+    fn process_input(&mut self) -> Result<bool> {
+        self.error = None;
+        self.status = None;
+        self.history_cursor = None;
+        self.history_draft.clear();
+        self.fold_frames.clear();
+        let input = self.input.clone();
+        self.record_history(&input);
+        if self.confirm_destructive && is_destructive(&input) {
+            self.status = Some(format!(
+                "{input}: press y to confirm, any other key to cancel"
+            ));
+            self.pending = Some(input);
+            self.repeat_count = None;
+            self.input.clear();
+            self.reset_cursor();
+            return Ok(true);
         }
-        Ok(())
+        let before = self.stack.clone();
+        let repeat = self.repeat_count.take().unwrap_or(1);
+        for _ in 0..repeat {
+            if !self.process_command(&input) {
+                return Ok(false);
+            }
+            if self.error.is_some() {
+                break;
+            }
+        }
+        self.track_expressions(&input, &before, repeat);
+        self.changed = changed_positions(&before, &self.stack);
+        self.input.clear();
+        self.reset_cursor();
+        Ok(true)
     }
 
-    fn process_input(&mut self) -> Result<bool> {
-        if let Ok(num) = self.input.parse::<f64>() {
+    /// Follows an input through `expressions`, falling back to the plain values whenever it
+    /// does something the infix view can't describe.
+    fn track_expressions(&mut self, input: &str, before: &[f64], repeat: usize) {
+        let as_literals = |values: &[f64]| {
+            values
+                .iter()
+                .map(|value| literal_expression(&value.to_string()))
+                .collect()
+        };
+        if self.expressions.len() != before.len() {
+            self.expressions = as_literals(before);
+        }
+        if self.error.is_none() {
+            let mut expressions = self.expressions.clone();
+            let commands = split_commands(input);
+            if (0..repeat).all(|_| {
+                commands
+                    .iter()
+                    .all(|command| infix_step(&mut expressions, command))
+            }) && expressions.len() == self.stack.len()
+            {
+                self.expressions = expressions;
+                return;
+            }
+        }
+        if changed_positions(before, &self.stack).is_empty() && before.len() == self.stack.len() {
+            return;
+        }
+        self.expressions = as_literals(&self.stack);
+    }
+
+    fn show_infix(&mut self) {
+        match self.expressions.last() {
+            Some((text, _)) if self.expressions.len() == self.stack.len() => {
+                self.status = Some(text.clone());
+            }
+            _ => self.error = Some(String::from("need 1 value, have 0")),
+        }
+    }
+
+    /// Runs the destructive command waiting for confirmation, or drops it.
+    /// Returns false if the confirmed command asks to quit.
+    fn answer_pending(&mut self, confirmed: bool) -> bool {
+        let Some(command) = self.pending.take() else {
+            return true;
+        };
+        self.status = None;
+        if confirmed {
+            let before = self.stack.clone();
+            let keep_running = self.process_command(&command);
+            self.changed = changed_positions(&before, &self.stack);
+            keep_running
+        } else {
+            self.status = Some(format!("{command} cancelled"));
+            true
+        }
+    }
+
+    /// Handles the quit shortcut, returning false to quit straight away, or true if quitting
+    /// with values on the stack waits for confirmation first.
+    fn request_quit(&mut self) -> bool {
+        if self.confirm_destructive && !self.stack.is_empty() {
+            self.status = Some(String::from(
+                "quit: press y to confirm, any other key to cancel",
+            ));
+            self.pending = Some(String::from("quit"));
+            return true;
+        }
+        false
+    }
+
+    /// Applies one line of input, returning false if it asks to quit. A line of several
+    /// commands, like `3 4 + 5 *`, runs as one undo step and is rolled back if any fails.
+    fn process_command(&mut self, input: &str) -> bool {
+        match split_commands(input)[..] {
+            [command] => self.process_token(command),
+            ref commands => self.run_as_one_step(self.stack.clone(), commands.iter().copied()),
+        }
+    }
+
+    /// Applies a single command or number, returning false if it asks to quit.
+    fn process_token(&mut self, input: &str) -> bool {
+        if let Some((operation, operand)) = combined_operation(input) {
+            self.perform_single_operand_operation(|a| operation(a, operand));
+        } else if let Ok(num) = input.parse::<f64>() {
             self.push_number(num);
+        } else if let Some(literal) = parse_radix_literal(input) {
+            match literal {
+                Ok(num) => self.push_number(num),
+                Err(err) => self.error = Some(err),
+            }
+        } else if let Some(units) = input.strip_prefix("convert ") {
+            self.perform_unit_conversion(units);
+        } else if let Some((name, body)) = input.split_once(" = ") {
+            self.define_formula(name.trim(), body);
+        } else if let Some(expression) = input.strip_prefix("eval ") {
+            self.evaluate(expression);
+        } else if let Some(values) = input.strip_prefix("set ") {
+            self.set_stack(values);
+        } else if let Some(command) = input.strip_prefix("dupapply ") {
+            self.apply_to_copies(command, 1);
+        } else if let Some(command) = input.strip_prefix("over2 ") {
+            self.apply_to_copies(command, 2);
+        } else if let Some(operator) = input.strip_prefix("reduce ") {
+            self.perform_reduce(operator);
+        } else if let Some(precision) = input.strip_prefix("fix ") {
+            self.set_precision(precision);
+        } else if let Some(mode) = input.strip_prefix("mode ") {
+            self.set_angle_mode(mode);
+        } else if let Some(name) = input.strip_prefix("sto ") {
+            self.store_variable(name.trim());
+        } else if let Some(name) = input.strip_prefix("rcl ") {
+            self.recall_variable(name.trim());
         } else {
-            match self.input.as_str() {
-                "quit" => return Ok(false),
-                "+" => self.perform_operation(|a, b| a + b),
-                "-" => self.perform_operation(|a, b| a - b),
-                "/" => self.perform_operation(|a, b| a / b),
-                "*" => self.perform_operation(|a, b| a * b),
-                "" => self.perform_clone(),
-                "%" => self.perform_operation(|a, b| a % b),
-                "^" => self.perform_operation(|a, b| b.powf(a)),
+            match input {
+                "quit" => return false,
+                "" | "clone" => self.perform_clone(),
+                "sigfig" => self.perform_operation(round_to_significant_figures),
+                // Reads like the usual atan2(y, x): `y x atan2`, unlike `^` which raises the top
+                "atan2" => self.perform_operation(|y, x| y.atan2(x)),
+                "pcterr" => self.perform_percent_error(),
+                // Both expect the price below the percentage, e.g. `100 8 markup` gives 108
+                "discount" => {
+                    self.perform_operation(|price, percent| price * (1.0 - percent / 100.0))
+                }
+                "markup" => {
+                    self.perform_operation(|price, percent| price * (1.0 + percent / 100.0))
+                }
+                "cents" => self.perform_single_operand_operation(round_to_cents),
+                "compound" => self.perform_ternary_operation(|principal, rate, periods| {
+                    principal * (1.0 + rate / 100.0).powf(periods)
+                }),
                 "neg" => self.perform_single_operand_operation(|a| -a),
                 "abs" => self.perform_single_operand_operation(|a| a.abs()),
                 "sqrt" => self.perform_single_operand_operation(|a| a.sqrt()),
-                "sin" => self.perform_single_operand_operation(|a| a.sin()),
-                "cos" => self.perform_single_operand_operation(|a| a.cos()),
-                "tan" => self.perform_single_operand_operation(|a| a.tan()),
-                "asin" => self.perform_single_operand_operation(|a| a.asin()),
-                "acos" => self.perform_single_operand_operation(|a| a.acos()),
-                "atan" => self.perform_single_operand_operation(|a| a.atan()),
                 "deg" => self.perform_single_operand_operation(|a| a.to_degrees()),
                 "rad" => self.perform_single_operand_operation(|a| a.to_radians()),
                 "!" => self.perform_factorial(),
                 "recip" => self.perform_single_operand_operation(|a| 1.0 / a),
+                "floor" => self.perform_single_operand_operation(f64::floor),
+                "ceil" => self.perform_single_operand_operation(f64::ceil),
+                "round" => self.perform_single_operand_operation(f64::round),
+                "trunc" => self.perform_single_operand_operation(f64::trunc),
+                "toint" => self.perform_to_int(),
+                "isint" => self.perform_single_operand_operation(|a| f64::from(a.fract() == 0.0)),
                 "log10" => self.perform_single_operand_operation(|a| a.log(10.0)),
                 "logn" => self.perform_single_operand_operation(|a| a.ln()),
                 "log2" => self.perform_single_operand_operation(|a| a.log(2.0)),
+                "exp" => self.perform_single_operand_operation(f64::exp),
+                "exp10" | "alog" => self.perform_single_operand_operation(|a| 10f64.powf(a)),
+                "decimals" => self.push_decimal_places(),
+                "copyprec" => self.copy_with_precision(),
+                "copyeng" => self.copy_engineering(),
+                "vadd" => self.perform_vector_operation(|a, b| a + b),
+                "vmul" => self.perform_vector_operation(|a, b| a * b),
+                "cross" => self.perform_cross_product(),
+                "norm" => self.perform_norm(),
+                "assert" => self.perform_assert(),
+                "print" => self.print_top(),
+                "baseline" => self.save_baseline(),
+                "checkbaseline" => self.check_baseline(),
+                "range" => self.perform_range(),
+                "countgt" => self.perform_count(|value, threshold| value > threshold),
+                "countlt" => self.perform_count(|value, threshold| value < threshold),
+                "counteq" => self.perform_count(approx_equal),
+                "filtergt" => self.perform_filter_gt(),
+                "argmax" => self.push_argmax(),
+                "normalizemax" => self.perform_normalize_max(),
+                "harmean" => self.perform_harmonic_mean(),
+                "sum" => self.collapse_stack(|values| values.iter().sum()),
+                "prod" => self.collapse_stack(|values| values.iter().product()),
+                "mean" => self.collapse_stack(mean),
+                "stddev" => self.collapse_stack(standard_deviation),
+                "lerp" => self.perform_ternary_operation(|a, b, t| a + (b - a) * t),
+                "cfrac" => self.perform_continued_fraction(),
+                "modpow" => self.perform_modular_power(),
+                "modinv" => self.perform_modular_inverse(),
+                "gcd" => self.perform_integer_operation("gcd", gcd),
+                "lcm" => self.perform_integer_operation("lcm", lcm),
+                "and" => self.perform_bitwise_operation("and", |a, b| Some(a & b)),
+                "or" => self.perform_bitwise_operation("or", |a, b| Some(a | b)),
+                "xor" => self.perform_bitwise_operation("xor", |a, b| Some(a ^ b)),
+                // Shift the second value by the top one, so `1 4 shl` gives 16
+                "shl" => self
+                    .perform_bitwise_operation("shl", |a, b| a.checked_shl(u32::try_from(b).ok()?)),
+                "shr" => self
+                    .perform_bitwise_operation("shr", |a, b| a.checked_shr(u32::try_from(b).ok()?)),
                 "swap" => self.perform_swap(),
+                "swap2" => self.perform_swap2(),
+                "rot" => self.perform_rot(),
+                "over" => self.perform_over(),
+                "rolln" => self.perform_roll_n(),
+                "swapat" => self.perform_swap_at(),
+                "pick" => self.perform_pick(),
+                "roll" => self.perform_roll(),
                 "clear" => self.perform_clear(),
                 "drop" => self.perform_drop(),
+                "dedup" => self.perform_dedup(),
+                "runlength" => self.perform_run_length(),
+                "pin" => self.perform_pin(),
+                "unpin" => self.perform_unpin(),
+                "keeptop" => self.perform_keep_top(),
+                "dupbottom" => self.perform_dup_bottom(),
                 "undo" => self.undo(),
                 "redo" => self.redo(),
+                "branch" => self.switch_branch(),
+                "noundo" => self.pause_undo(),
+                "undoon" => self.resume_undo(),
                 "inf" => self.push_infinity(),
                 "pi" => self.push_pi(),
-                _ => (),
+                "summary" => self.show_summary = !self.show_summary,
+                "result" => self.show_result = !self.show_result,
+                "index" => self.show_indices = !self.show_indices,
+                "debugmode" => self.debug_mode = !self.debug_mode,
+                "debug" => self.dump_debug_state(),
+                "bits" => self.show_bits = !self.show_bits,
+                "hist" => self.show_histogram = !self.show_histogram,
+                "plot" => self.show_plot = !self.show_plot,
+                "spark" => self.show_sparkline = !self.show_sparkline,
+                "confirm" => self.confirm_destructive = !self.confirm_destructive,
+                "std" => self.precision = None,
+                "zeros" => self.trailing_zeros = !self.trailing_zeros,
+                "animate" => self.animate_folds = !self.animate_folds,
+                "saveconfig" => self.save_config(),
+                "infix" => self.show_infix(),
+                command => {
+                    if let Some((_, operation)) =
+                        BINARY_OPERATORS.iter().find(|(name, _)| *name == command)
+                    {
+                        self.perform_operation(*operation);
+                    } else if let Some((_, value)) =
+                        CONSTANTS.iter().find(|(name, _)| *name == command)
+                    {
+                        self.push_number(*value);
+                    } else if let Some((_, conversion)) = TEMPERATURE_CONVERSIONS
+                        .iter()
+                        .find(|(name, _)| *name == command)
+                    {
+                        self.perform_single_operand_operation(conversion);
+                    } else if let Some(&(_, angle, function)) =
+                        TRIG_FUNCTIONS.iter().find(|(name, _, _)| *name == command)
+                    {
+                        let mode = self.angle_mode;
+                        self.perform_single_operand_operation(|a| {
+                            apply_trig(angle, function, mode, a).1
+                        });
+                    } else if let Some(formula) = self.formulas.get(command) {
+                        let (body, arity) = (formula.body.clone(), formula.arity);
+                        self.apply_formula(&body, arity);
+                    } else if is_known_command(command) {
+                        // Only commands that take arguments, like `convert`, get here
+                        self.error = Some(format!("{command} is missing its arguments"));
+                    } else if let Some(suggestion) = suggest_command(command) {
+                        self.error = Some(format!(
+                            "unknown command: {command}, did you mean: {suggestion}?"
+                        ));
+                    } else {
+                        self.error = Some(format!("unknown command: {command}"));
+                    }
+                }
             }
         }
-        self.input.clear();
-        self.reset_cursor();
-        self.save_stack()?;
-        Ok(true)
+        true
     }
 
-    fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
-        loop {
-            terminal.draw(|frame| self.draw(frame))?;
+    /// Runs `action`, turning a panic into an error message and putting the stack, undo
+    /// history, pinned values and variables back the way they were, so a bug in one command
+    /// can't leave the terminal in raw mode.
+    fn catch_panics(&mut self, action: fn(&mut App) -> Result<bool>) -> Result<bool> {
+        let stack = self.stack.clone();
+        let undo = self.undo.clone();
+        let pinned = self.pinned.clone();
+        let variables = self.variables.clone();
+        // The default hook would print over the TUI, so silence it while the action runs
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let outcome = panic::catch_unwind(AssertUnwindSafe(|| action(self)));
+        panic::set_hook(hook);
+
+        outcome.unwrap_or_else(|payload| {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| String::from("unknown panic"));
+            self.stack = stack;
+            self.undo = undo;
+            self.pinned = pinned;
+            self.variables = variables;
+            self.input.clear();
+            self.reset_cursor();
+            self.error = Some(format!("internal error: {message}"));
+            Ok(true)
+        })
+    }
 
-            if let Event::Key(key) = event::read()? {
-                match self.input_mode {
-                    InputMode::Normal => match key.code {
-                        KeyCode::Char('e') => {
-                            self.input_mode = InputMode::Editing;
+    /// Runs each line of `reader` as input, printing to stdout and stopping at the first error.
+    fn run_batch(mut self, reader: impl BufRead) -> Result<ExitCode> {
+        self.output = Output::Stdout;
+        // A script has nobody to answer the prompt
+        self.confirm_destructive = false;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            self.input = line;
+            let keep_running = self.process_input()?;
+            if let Some(error) = &self.error {
+                eprintln!("error: {error}");
+                return Ok(ExitCode::FAILURE);
+            }
+            if !keep_running {
+                break;
+            }
+        }
+        Ok(ExitCode::SUCCESS)
+    }
+
+    /// Adds a digit to the count typed in Normal mode, capped at `MAX_REPEAT`.
+    fn push_repeat_digit(&mut self, digit: u32) {
+        let count = self.repeat_count.unwrap_or(0);
+        self.repeat_count = Some((count * 10 + digit as usize).min(MAX_REPEAT));
+    }
+
+    fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
+        self.load_state();
+        self.history = App::load_history().unwrap_or_default();
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            if let Event::Key(key) = event::read()? {
+                // The highlight and any error only last until the next keystroke
+                self.changed.clear();
+                self.error = None;
+                if self.pending.is_some() && key.kind == KeyEventKind::Press {
+                    let keep_running = self.answer_pending(key.code == KeyCode::Char('y'));
+                    self.save_state()?;
+                    if !keep_running {
+                        return self.save_history();
+                    }
+                    continue;
+                }
+                match self.input_mode {
+                    InputMode::Normal => match key.code {
+                        KeyCode::Char(digit @ '0'..='9') => {
+                            self.push_repeat_digit(digit.to_digit(10).unwrap_or_default());
+                        }
+                        KeyCode::Char('e') => {
+                            self.input_mode = InputMode::Editing;
                         }
                         KeyCode::Char('q') => {
-                            return Ok(());
+                            self.save_state()?;
+                            return self.save_history();
+                        }
+                        KeyCode::PageDown => {
+                            self.stack_offset = page_offset(
+                                self.stack_offset,
+                                self.stack_page_size.get(),
+                                self.stack.len(),
+                                true,
+                            );
+                        }
+                        KeyCode::PageUp => {
+                            self.stack_offset = page_offset(
+                                self.stack_offset,
+                                self.stack_page_size.get(),
+                                self.stack.len(),
+                                false,
+                            );
                         }
                         _ => {}
                     },
                     InputMode::Editing if key.kind == KeyEventKind::Press => match key.code {
                         KeyCode::Enter => {
-                            if !self.process_input()? {
-                                return Ok(());
+                            let keep_running = self.catch_panics(App::process_input)?;
+                            if !keep_running {
+                                self.save_state()?;
+                                return self.save_history();
+                            }
+                            self.play_fold_animation(&mut terminal)?;
+                            self.save_state()?;
+                        }
+                        KeyCode::F(9) => self.toggle_input_sign(),
+                        KeyCode::Char('-') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            self.toggle_input_sign()
+                        }
+                        KeyCode::Char('p') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            self.insert_constant(f64::consts::PI)
+                        }
+                        KeyCode::Char('e') if key.modifiers.contains(KeyModifiers::ALT) => {
+                            self.insert_constant(f64::consts::E)
+                        }
+                        KeyCode::Char('q' | 'c')
+                            if key.modifiers.contains(KeyModifiers::CONTROL) =>
+                        {
+                            if self.request_quit() {
+                                continue;
                             }
+                            self.save_state()?;
+                            return self.save_history();
                         }
                         KeyCode::Char(to_insert) => self.enter_char(to_insert),
+                        KeyCode::Backspace
+                            if key
+                                .modifiers
+                                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+                        {
+                            self.delete_command_or_char()
+                        }
                         KeyCode::Backspace => self.delete_char(),
                         KeyCode::Left => self.move_cursor_left(),
                         KeyCode::Right => self.move_cursor_right(),
+                        KeyCode::Up => self.recall_previous(),
+                        KeyCode::Down => self.recall_next(),
                         KeyCode::Esc => self.input_mode = InputMode::Normal,
                         _ => {}
                     },
@@ -212,22 +1733,59 @@ impl App {
         }
     }
 
+    /// Draws each step of the last animated fold in turn, then puts the real stack back.
+    fn play_fold_animation(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        let frames = std::mem::take(&mut self.fold_frames);
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let stack = std::mem::take(&mut self.stack);
+        for frame_stack in frames {
+            self.stack = frame_stack;
+            terminal.draw(|frame| self.draw(frame))?;
+            // Any key skips the rest of the animation
+            if event::poll(FOLD_FRAME_DELAY)? && matches!(event::read()?, Event::Key(_)) {
+                break;
+            }
+        }
+        self.stack = stack;
+        Ok(())
+    }
+
     fn draw(&self, frame: &mut Frame) {
         let vertical = Layout::vertical([
+            Constraint::Length(u16::from(self.show_summary)),
             Constraint::Length(1),
             Constraint::Length(3),
+            Constraint::Length(1),
+            Constraint::Length(if self.show_result { 3 } else { 0 }),
+            Constraint::Length(if self.show_bits { 3 } else { 0 }),
+            Constraint::Length(if self.show_histogram { 12 } else { 0 }),
+            Constraint::Length(if self.show_plot { 12 } else { 0 }),
             Constraint::Min(1),
         ]);
-        let [help_area, input_area, messages_area] = vertical.areas(frame.area());
+        let [summary_area, help_area, input_area, error_area, result_area, bits_area, histogram_area, plot_area, messages_area] =
+            vertical.areas(frame.area());
+
+        if self.show_summary {
+            frame.render_widget(Paragraph::new(self.inline_summary()), summary_area);
+        }
 
-        let (msg, style) = match self.input_mode {
+        let (mut msg, style) = match self.input_mode {
             InputMode::Normal => (
                 vec![
                     "Press ".into(),
                     "q".bold(),
                     " to exit, ".into(),
                     "e".bold(),
-                    " to start editing.".bold(),
+                    " to start editing, ".bold(),
+                    "PgUp/PgDn".bold(),
+                    " to scroll, ".into(),
+                    "0-9".bold(),
+                    match self.repeat_count {
+                        Some(count) => format!(" to repeat the next input ({count}x).").into(),
+                        None => " to repeat the next input.".into(),
+                    },
                 ],
                 Style::default().add_modifier(Modifier::RAPID_BLINK),
             ),
@@ -236,12 +1794,21 @@ impl App {
                     "Press ".into(),
                     "Esc".bold(),
                     " to stop editing, ".into(),
+                    "Ctrl+Q".bold(),
+                    " to quit, ".into(),
+                    "F9".bold(),
+                    " to toggle the sign, ".into(),
+                    "Alt+p/Alt+e".bold(),
+                    " to type pi/e, ".into(),
+                    "Up/Down".bold(),
+                    " to recall inputs, ".into(),
                     "Enter".bold(),
                     " to add the number to stack or perform operation".into(),
                 ],
                 Style::default(),
             ),
         };
+        msg.push(format!(" Angles in {}.", self.angle_mode.name()).into());
         let text = Text::from(Line::from(msg)).patch_style(style);
         let help_message = Paragraph::new(text);
         frame.render_widget(help_message, help_area);
@@ -269,47 +1836,96 @@ impl App {
             )),
         }
 
+        if let Some(error) = &self.error {
+            let error_message =
+                Paragraph::new(error.as_str()).style(Style::default().fg(Color::Red));
+            frame.render_widget(error_message, error_area);
+        } else if let Some(status) = &self.status {
+            frame.render_widget(Paragraph::new(status.as_str()), error_area);
+        } else if let Some(hint) = self.trig_hint() {
+            frame.render_widget(Paragraph::new(hint).dim(), error_area);
+        } else if self.show_sparkline {
+            frame.render_widget(Paragraph::new(sparkline(&self.stack)), error_area);
+        }
+
+        if self.show_result {
+            let result = Paragraph::new(self.result_text())
+                .style(Style::default().add_modifier(Modifier::BOLD))
+                .centered()
+                .block(Block::bordered().title("Result"));
+            frame.render_widget(result, result_area);
+        }
+
+        if self.show_bits {
+            let bits = Paragraph::new(self.bits_text()).block(Block::bordered().title("Bits"));
+            frame.render_widget(bits, bits_area);
+        }
+
+        if self.show_histogram {
+            frame.render_widget(self.histogram(), histogram_area);
+        }
+
+        if self.show_plot {
+            let points = plot_points(&self.stack);
+            frame.render_widget(self.plot(&points), plot_area);
+        }
+
         let horizontal =
             Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]);
         let [stack_area, operations_area] = horizontal.areas(messages_area);
 
+        // Leave out the borders, and keep the offset valid if the stack has shrunk since
+        let page_size = usize::from(stack_area.height.saturating_sub(2)).max(1);
+        self.stack_page_size.set(page_size);
+        let offset = self
+            .stack_offset
+            .min(self.stack.len().saturating_sub(page_size));
         let stack: Vec<ListItem> = self
             .stack
             .iter()
             .rev()
             .enumerate()
+            .skip(offset)
             .map(|(i, m)| {
-                let content = Line::from(Span::raw(format!("{i}: {m}")));
-                ListItem::new(content)
+                let content = Line::from(Span::raw(self.format_stack_entry(i, *m)));
+                let position = self.stack.len() - 1 - i;
+                if self.changed.contains(&position) {
+                    ListItem::new(content).style(Style::default().fg(Color::Green).bold())
+                } else {
+                    ListItem::new(content)
+                }
             })
             .collect();
-        let stack = List::new(stack).block(Block::bordered().title("Stack"));
+        let stack = List::new(stack).block(
+            Block::bordered()
+                .title("Stack")
+                .title(Line::from(self.pinned_text()).right_aligned()),
+        );
         frame.render_widget(stack, stack_area);
 
-        let operations = [
-            ("Binary", &["+", "-", "*", "/", "%", "^"] as &[_]),
-            (
-                "Unary",
-                &[
-                    "neg", "abs", "sqrt", "sin", "cos", "tan", "asin", "acos", "atan", "deg",
-                    "rad", "!", "recip", "log10", "logn", "log2",
-                ],
-            ),
-            ("Stack", &["swap", "clear", "drop", "clone (empty)"]),
-            ("History", &["undo", "redo"]),
-            ("Constants", &["inf", "pi"]),
-            ("Program", &["quit"]),
-        ];
-
         let mut lines = Vec::new();
-        for (category, ops) in &operations {
+        for (category, ops) in OPERATIONS {
+            let mut ops: Vec<String> = ops
+                .iter()
+                .map(|(name, arity)| format_operation(name, *arity))
+                .collect();
+            if *category == "Conversions" {
+                ops.splice(
+                    0..0,
+                    TEMPERATURE_CONVERSIONS
+                        .iter()
+                        .map(|(name, _)| format_operation(name, Arity::Fixed(1))),
+                );
+            } else if *category == "Constants" {
+                ops.extend(CONSTANTS.iter().map(|(name, _)| name.to_string()));
+            }
             lines.push(Line::from(Span::styled(
                 *category,
                 Style::default()
                     .add_modifier(Modifier::BOLD)
                     .add_modifier(Modifier::UNDERLINED),
             )));
-            for op_chunk in ops.chunks(6) {
+            for op_chunk in ops.chunks(4) {
                 lines.push(Line::from(format!("  {}", op_chunk.join(" "))));
             }
             lines.push(Line::from("")); // empty line for spacing
@@ -321,42 +1937,294 @@ impl App {
         frame.render_widget(operations_guide, operations_area);
     }
 
+    fn set_precision(&mut self, precision: &str) {
+        match precision.trim().parse::<usize>() {
+            Ok(precision) if precision <= MAX_PRECISION => self.precision = Some(precision),
+            _ => {
+                self.error = Some(format!(
+                    "precision must be a whole number from 0 to {MAX_PRECISION}"
+                ))
+            }
+        }
+    }
+
+    fn set_angle_mode(&mut self, mode: &str) {
+        match mode.trim() {
+            "deg" => self.angle_mode = AngleMode::Degrees,
+            "rad" => self.angle_mode = AngleMode::Radians,
+            other => self.error = Some(format!("unknown angle mode: {other}, expected deg or rad")),
+        }
+    }
+
+    fn format_value(&self, value: f64) -> String {
+        let formatted = match self.precision {
+            None => value.to_string(),
+            Some(precision) => {
+                let formatted = format_with_precision(value, precision);
+                if self.trailing_zeros || !formatted.contains('.') {
+                    formatted
+                } else {
+                    formatted
+                        .trim_end_matches('0')
+                        .trim_end_matches('.')
+                        .to_string()
+                }
+            }
+        };
+        // -0.0, or a tiny negative rounded away, reads as a distinct value when it isn't one
+        match formatted.strip_prefix('-') {
+            Some(unsigned)
+                if !self.debug_mode && unsigned.chars().all(|c| matches!(c, '0' | '.')) =>
+            {
+                unsigned.to_string()
+            }
+            _ => formatted,
+        }
+    }
+
+    fn format_stack_entry(&self, index: usize, value: f64) -> String {
+        if self.show_indices {
+            format!("{index}: {}", self.format_value(value))
+        } else {
+            self.format_value(value)
+        }
+    }
+
+    /// Formats the top three stack values on one line, top of stack last.
+    fn inline_summary(&self) -> String {
+        let shown = self.stack.len().min(3);
+        let values: Vec<String> = self.stack[self.stack.len() - shown..]
+            .iter()
+            .map(|value| self.format_value(*value))
+            .collect();
+        let summary = values.join(" | ");
+        if self.stack.len() > shown {
+            format!("… {summary}")
+        } else {
+            summary
+        }
+    }
+
+    /// Previews a trig command being typed on the top of the stack, naming its angle unit so
+    /// e.g. `90 sin` in radians isn't mistaken for degrees.
+    fn trig_hint(&self) -> Option<String> {
+        let top = *self.stack.last()?;
+        let (unit, result) = trig_preview(&self.input, top, self.angle_mode)?;
+        Some(format!(
+            "{}({}) in {unit} = {}",
+            self.input,
+            self.format_value(top),
+            self.format_value(result)
+        ))
+    }
+
+    /// The stack as a bar chart, bottom to top, with negative values drawn in red.
+    fn histogram(&self) -> BarChart<'_> {
+        let bars: Vec<Bar> = histogram_bars(&self.stack)
+            .into_iter()
+            .zip(&self.stack)
+            .map(|((height, negative), value)| {
+                let bar = Bar::default()
+                    .value(height)
+                    .text_value(self.format_value(*value));
+                if negative {
+                    bar.style(Style::default().fg(Color::Red))
+                } else {
+                    bar
+                }
+            })
+            .collect();
+        BarChart::default()
+            .block(Block::bordered().title("Histogram"))
+            .bar_width(5)
+            .bar_gap(1)
+            .max(HISTOGRAM_SCALE)
+            .data(BarGroup::default().bars(&bars))
+    }
+
+    /// The stack as a line chart, with the bottom of the stack at x = 0.
+    fn plot<'a>(&self, points: &'a [(f64, f64)]) -> Chart<'a> {
+        let ([x_min, x_max], [y_min, y_max]) = plot_bounds(points);
+        let dataset = Dataset::default()
+            .marker(symbols::Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::Cyan))
+            .data(points);
+        Chart::new(vec![dataset])
+            .block(Block::bordered().title("Plot"))
+            .x_axis(
+                Axis::default()
+                    .bounds([x_min, x_max])
+                    .labels([x_min.to_string(), x_max.to_string()]),
+            )
+            .y_axis(
+                Axis::default()
+                    .bounds([y_min, y_max])
+                    .labels([self.format_value(y_min), self.format_value(y_max)]),
+            )
+    }
+
+    fn bits_text(&self) -> String {
+        let Some(&top) = self.stack.last() else {
+            return String::new();
+        };
+        let (sign, exponent, mantissa) = decompose_bits(top);
+        format!(
+            "{:#018x}  sign {sign}  exponent {exponent} (2^{})  mantissa {mantissa:#015x}",
+            top.to_bits(),
+            exponent as i64 - 1023
+        )
+    }
+
+    fn result_text(&self) -> String {
+        self.stack
+            .last()
+            .map(|value| format!("= {}", self.format_value(*value)))
+            .unwrap_or_default()
+    }
+
     fn push_number(&mut self, num: f64) {
-        self.undo.push(self.stack.clone());
+        self.push_undo_top(0);
         self.stack.push(num);
-        self.redo.clear();
     }
 
     fn push_infinity(&mut self) {
-        self.undo.push(self.stack.clone());
+        self.push_undo_top(0);
         self.stack.push(f64::INFINITY);
-        self.redo.clear();
     }
 
     fn push_pi(&mut self) {
-        self.undo.push(self.stack.clone());
+        self.push_undo_top(0);
         self.stack.push(f64::consts::PI);
-        self.redo.clear();
+    }
+
+    fn push_decimal_places(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        let top = self.stack[self.stack.len() - 1];
+        self.push_number(decimal_places(top) as f64);
+    }
+
+    /// Pops a precision and copies the value beneath it to the clipboard with that many decimals.
+    fn copy_with_precision(&mut self) {
+        if !self.require_values(2) {
+            return;
+        }
+        let precision = self.stack[self.stack.len() - 1];
+        if !(0.0..=MAX_PRECISION as f64).contains(&precision) || precision.fract() != 0.0 {
+            self.error = Some(format!(
+                "precision must be a whole number from 0 to {MAX_PRECISION}"
+            ));
+            return;
+        }
+        let text = format_with_precision(self.stack[self.stack.len() - 2], precision as usize);
+        // Keep the precision on the stack if the copy fails, like any other failed command
+        if self.copy_to_clipboard(text) {
+            self.perform_drop();
+        }
+    }
+
+    /// Copies the top of the stack to the clipboard in engineering notation, e.g. `1.5k`.
+    fn copy_engineering(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        let top = self.stack[self.stack.len() - 1];
+        self.copy_to_clipboard(format_engineering(top));
+    }
+
+    /// Puts `text` on the clipboard, returning false and setting the error if it can't.
+    fn copy_to_clipboard(&mut self, text: String) -> bool {
+        if self.clipboard.is_none() {
+            match arboard::Clipboard::new() {
+                Ok(clipboard) => self.clipboard = Some(clipboard),
+                Err(err) => {
+                    self.error = Some(format!("clipboard unavailable: {err}"));
+                    return false;
+                }
+            }
+        }
+        if let Some(clipboard) = &mut self.clipboard {
+            if let Err(err) = clipboard.set_text(text) {
+                self.error = Some(format!("could not copy: {err}"));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Snapshots the whole stack so the next change can be undone. For changes that can reach
+    /// anywhere in the stack; the rest use `push_undo_top`.
+    fn push_undo(&mut self) {
+        self.push_undo_top(self.stack.len());
+    }
+
+    /// Saves the top `depth` values so the next change, which leaves the rest alone, can be
+    /// undone. Does nothing if recording is turned off, and drops the oldest states beyond
+    /// `max_undo`.
+    fn push_undo_top(&mut self, depth: usize) {
+        if self.record_undo && self.max_undo > 0 {
+            self.undo.record(&self.stack, depth);
+            self.undo.prune(self.max_undo);
+        }
+    }
+
+    /// Adds a change outside the stack to the undo step just recorded with `push_undo`.
+    fn note_undo(&mut self, change: SideChange) {
+        if self.record_undo && self.max_undo > 0 {
+            self.undo.note(change);
+        }
+    }
+
+    fn pause_undo(&mut self) {
+        if self.record_undo {
+            self.paused_stack = Some(self.stack.clone());
+            self.record_undo = false;
+        }
+    }
+
+    /// Turns recording back on. The undo steps only hold what each change touched, so they
+    /// can't be replayed over a stack changed off the record; if it was, history starts over.
+    fn resume_undo(&mut self) {
+        self.record_undo = true;
+        if let Some(paused) = self.paused_stack.take() {
+            if paused != self.stack {
+                self.undo = UndoTree::default();
+            }
+        }
     }
 
     fn undo(&mut self) {
-        if let Some(previous_state) = self.undo.pop() {
-            // Restore the previous state of the stack.
-            //
-            self.redo.push(self.stack.clone());
-            self.stack = previous_state;
-        } else {
-            println!("Nothing to undo");
+        match self.undo.undo(&mut self.stack) {
+            Some(side) => {
+                for change in side.iter().rev() {
+                    change.undo(&mut self.pinned, &mut self.variables);
+                }
+            }
+            None => self.error = Some(String::from("nothing to undo")),
         }
     }
 
     fn redo(&mut self) {
-        if let Some(redo_state) = self.redo.pop() {
-            //
-            self.undo.push(self.stack.clone());
-            self.stack = redo_state;
-        } else {
-            println!("Nothing to redo");
+        match self.undo.redo(&mut self.stack) {
+            Some(side) => {
+                for change in side {
+                    change.redo(&mut self.pinned, &mut self.variables);
+                }
+            }
+            None => self.error = Some(String::from("nothing to redo")),
+        }
+    }
+
+    /// Points `redo` at the next branch made from the current state, in the order they were
+    /// made.
+    fn switch_branch(&mut self) {
+        match self.undo.next_branch() {
+            Some((branch, count)) => {
+                self.status = Some(format!("redo follows branch {branch} of {count}"));
+            }
+            None => self.error = Some(String::from("no other branches here")),
         }
     }
 
@@ -364,792 +2232,4857 @@ impl App {
     where
         F: FnOnce(f64) -> f64,
     {
-        if self.stack.is_empty() {
+        if !self.require_values(1) {
             return;
         }
 
-        self.undo.push(self.stack.clone()); // Save the current state for undo
+        self.push_undo_top(1); // Save the current state for undo
         let a = self.stack.pop().unwrap(); // Pop the operand
         let result = operation(a); // Apply the operation
         self.stack.push(result); // Push the result back onto the stack
-        self.redo.clear();
     }
 
     fn perform_operation(&mut self, operation: fn(f64, f64) -> f64) {
-        if self.stack.len() < 2 {
+        if !self.require_values(2) {
             return;
         }
-        self.undo.push(self.stack.clone());
+        self.push_undo_top(2);
         let b = self.stack.pop().unwrap();
         let a = self.stack.pop().unwrap();
         let result = operation(a, b);
         self.stack.push(result);
-        self.redo.clear();
     }
 
-    fn perform_clone(&mut self) {
-        if self.stack.is_empty() {
+    fn perform_ternary_operation(&mut self, operation: fn(f64, f64, f64) -> f64) {
+        if !self.require_values(3) {
             return;
         }
-        self.undo.push(self.stack.clone());
+        self.push_undo_top(3);
+        let c = self.stack.pop().unwrap();
+        let b = self.stack.pop().unwrap();
         let a = self.stack.pop().unwrap();
-        self.stack.push(a);
-        self.stack.push(a);
-        self.redo.clear();
+        let result = operation(a, b, c);
+        self.stack.push(result);
     }
 
-    fn perform_factorial(&mut self) {
-        if self.stack.is_empty() {
-            return;
+    /// Replaces `base exponent modulus`, each rounded to an integer, with base^exponent mod
+    /// modulus.
+    fn perform_modular_power(&mut self) {
+        if let [.., base, exponent, modulus] = self.stack[..] {
+            if let Some(value) = [base, exponent, modulus]
+                .into_iter()
+                .find(|value| !is_exact_integer(*value))
+            {
+                self.error = Some(format!(
+                    "modpow needs integers no larger than 2^53, got {}",
+                    self.format_value(value)
+                ));
+                return;
+            }
+            if modulus.round() == 0.0 {
+                self.error = Some(String::from("modpow needs a non-zero modulus"));
+                return;
+            }
+            if exponent.round() < 0.0 {
+                self.error = Some(String::from("modpow needs a non-negative exponent"));
+                return;
+            }
         }
+        self.perform_ternary_operation(modular_power);
+    }
 
-        self.undo.push(self.stack.clone());
-        let a = self.stack.pop().unwrap();
-        let abs_a = a.abs();
-
-        fn factorial(n: u64) -> f64 {
-            let mut result = 1.0;
-            for i in 1..=n {
-                result *= i as f64;
+    /// Replaces `expected measured` with how far off the measurement is, as a percentage of the
+    /// expected value.
+    fn perform_percent_error(&mut self) {
+        if let [.., expected, _] = self.stack[..] {
+            if expected == 0.0 {
+                self.error = Some(String::from("pcterr needs a non-zero expected value"));
+                return;
             }
-            result
         }
+        self.perform_operation(|expected, measured| {
+            (measured - expected).abs() / expected.abs() * 100.0
+        });
+    }
 
-        let rounded_a = abs_a.round() as u64; // Round to the nearest integer and cast to u64
-
-        // Calculate factorial
-        let result = factorial(rounded_a);
+    /// Replaces `value modulus`, both rounded to integers, with the x in `0..|modulus|` for which
+    /// value * x mod modulus is 1.
+    fn perform_modular_inverse(&mut self) {
+        if !self.require_values(2) {
+            return;
+        }
+        let value = self.stack[self.stack.len() - 2];
+        let modulus = self.stack[self.stack.len() - 1];
+        if let Some(operand) = [value, modulus]
+            .into_iter()
+            .find(|operand| !is_exact_integer(*operand))
+        {
+            self.error = Some(format!(
+                "modinv needs integers no larger than 2^53, got {}",
+                self.format_value(operand)
+            ));
+            return;
+        }
+        let Some(inverse) = modular_inverse(value.round() as i128, modulus.round() as i128) else {
+            self.error = Some(format!("{value} has no inverse modulo {modulus}"));
+            return;
+        };
 
-        self.stack.push(result);
-        self.redo.clear();
+        self.push_undo_top(2);
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(inverse as f64);
     }
 
-    fn perform_swap(&mut self) {
-        if self.stack.len() < 2 {
+    /// Applies `operation` to the top two values rounded to integers, which must be
+    /// non-negative and pass `is_exact_integer`.
+    fn perform_integer_operation(&mut self, name: &str, operation: fn(u128, u128) -> u128) {
+        if !self.require_values(2) {
+            return;
+        }
+        let operands = [
+            self.stack[self.stack.len() - 2],
+            self.stack[self.stack.len() - 1],
+        ];
+        if let Some(value) = operands
+            .iter()
+            .find(|value| !is_exact_integer(**value) || value.round() < 0.0)
+        {
+            self.error = Some(format!(
+                "{name} needs non-negative integers no larger than 2^53, got {}",
+                self.format_value(*value)
+            ));
             return;
         }
-        self.undo.push(self.stack.clone());
-        let b = self.stack.pop().unwrap();
-        let a = self.stack.pop().unwrap();
-        self.stack.push(b);
-        self.stack.push(a);
-        self.redo.clear();
-    }
 
-    fn perform_clear(&mut self) {
-        self.undo.push(self.stack.clone());
-        self.stack.clear();
+        self.push_undo_top(2);
+        self.stack.truncate(self.stack.len() - 2);
+        let [a, b] = operands.map(|value| value.round() as u128);
+        self.stack.push(operation(a, b) as f64);
     }
 
-    fn perform_drop(&mut self) {
-        if self.stack.is_empty() {
+    /// Applies `operation` to the top two values as `i64`s. Fractional or out-of-range operands
+    /// are refused rather than truncated, as is a shift `operation` reports as `None`.
+    fn perform_bitwise_operation(&mut self, name: &str, operation: fn(i64, i64) -> Option<i64>) {
+        if !self.require_values(2) {
             return;
         }
-        self.undo.push(self.stack.clone());
-        self.stack.pop().unwrap();
+        let operands = [
+            self.stack[self.stack.len() - 2],
+            self.stack[self.stack.len() - 1],
+        ];
+        let [Some(a), Some(b)] = operands.map(exact_i64) else {
+            let value = operands
+                .into_iter()
+                .find(|value| exact_i64(*value).is_none());
+            self.error = Some(format!(
+                "{name} needs integers in i64 range, got {}",
+                self.format_value(value.unwrap())
+            ));
+            return;
+        };
+        let Some(result) = operation(a, b) else {
+            self.error = Some(format!("{name} can't shift by {b}, expected 0 to 63"));
+            return;
+        };
+
+        self.push_undo_top(2);
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(result as f64);
     }
-}
 
-#[cfg(test)]
-mod tests {
+    fn perform_unit_conversion(&mut self, units: &str) {
+        let find_unit = |name: &str| UNITS.iter().find(|(unit, _, _)| *unit == name);
 
-    use super::App;
+        let mut names = units.split_whitespace();
+        let (Some(from), Some(to), None) = (names.next(), names.next(), names.next()) else {
+            self.error = Some(String::from("usage: convert <from> <to>"));
+            return;
+        };
+        let Some((_, from_quantity, from_size)) = find_unit(from) else {
+            self.error = Some(format!("unknown unit: {from}"));
+            return;
+        };
+        let Some((_, to_quantity, to_size)) = find_unit(to) else {
+            self.error = Some(format!("unknown unit: {to}"));
+            return;
+        };
+        if from_quantity != to_quantity {
+            self.error = Some(format!("cannot convert {from_quantity} to {to_quantity}"));
+            return;
+        }
 
-    mod tui {
+        let factor = from_size / to_size;
+        self.perform_single_operand_operation(|a| a * factor);
+    }
 
-        use super::App;
-        #[test]
-        fn cursor_movement_left() {
-            let mut app = App::new();
-            app.input = String::from("hello");
-            app.character_index = 3;
-            app.move_cursor_left();
-            assert_eq!(app.character_index, 2);
+    fn perform_clone(&mut self) {
+        if !self.require_values(1) {
+            return;
         }
+        self.push_undo_top(1);
+        let a = self.stack.pop().unwrap();
+        self.stack.push(a);
+        self.stack.push(a);
+    }
 
-        #[test]
-        fn cursor_movement_right() {
-            let mut app = App::new();
-            app.input = String::from("hello");
-            app.character_index = 1;
-            app.move_cursor_right();
-            assert_eq!(app.character_index, 2);
+    /// Copies the bottom-most value to the top.
+    fn perform_dup_bottom(&mut self) {
+        if !self.require_values(1) {
+            return;
         }
+        self.push_undo_top(0);
+        self.stack.push(self.stack[0]);
+    }
 
-        #[test]
-        fn enter_char() {
-            let mut app = App::new();
-            app.enter_char('a');
-            assert_eq!(app.input, "a");
-            assert_eq!(app.character_index, 1);
-        }
+    /// Runs a quoted RPN expression like `"2 3 +"` on an empty stack of its own and pushes its
+    /// final value.
+    fn evaluate(&mut self, expression: &str) {
+        let Some(expression) = expression
+            .trim()
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+        else {
+            self.error = Some(String::from("usage: eval \"<rpn expression>\""));
+            return;
+        };
 
-        #[test]
-        fn delete_char() {
-            let mut app = App::new();
-            app.input = String::from("hello");
-            app.character_index = 3;
-            app.delete_char();
-            assert_eq!(app.input, "helo");
-            assert_eq!(app.character_index, 2);
+        // A separate stack, but the same angle mode, variables and formulas
+        let mut sub = App::new();
+        sub.record_undo = false;
+        sub.angle_mode = self.angle_mode;
+        sub.variables = self.variables.clone();
+        sub.formulas = self.formulas.clone();
+        for token in split_commands(expression) {
+            if !sub.process_token(token) {
+                self.error = Some(String::from("eval cannot quit"));
+                return;
+            }
+            if let Some(error) = sub.error {
+                self.error = Some(format!("in eval: {error}"));
+                return;
+            }
+        }
+        match sub.stack.last() {
+            Some(&result) => self.push_number(result),
+            None => self.error = Some(String::from("eval left nothing on its stack")),
         }
     }
 
-    #[allow(clippy::approx_constant)]
-    mod process_input {
+    /// Replaces the whole stack with space-separated numbers, e.g. `set 1 2 3`.
+    fn set_stack(&mut self, values: &str) {
+        if values.trim().is_empty() {
+            self.error = Some(String::from("set is missing its arguments"));
+            return;
+        }
+        let parsed: Result<Vec<f64>, _> = values
+            .split_whitespace()
+            .map(|token| token.parse::<f64>().map_err(|_| token))
+            .collect();
+        match parsed {
+            Ok(stack) => {
+                self.push_undo();
+                self.stack = stack;
+            }
+            Err(token) => self.error = Some(format!("set expects numbers, got {token}")),
+        }
+    }
 
-        use core::f64;
+    /// Runs a command that takes `count` values on copies of the top `count`, so the originals
+    /// stay below the result. The whole step undoes at once.
+    fn apply_to_copies(&mut self, command: &str, count: usize) {
+        if command_arity(command) != Some(Arity::Fixed(count)) {
+            self.error = Some(format!("{command} does not take exactly {count} value(s)"));
+            return;
+        }
+        if !self.require_values(count) {
+            return;
+        }
 
-        use super::App;
+        let before = self.stack.clone();
+        self.stack.extend_from_within(before.len() - count..);
+        self.run_as_one_step(before, [command]);
+    }
 
-        #[test]
-        fn addition() {
+    /// Pops the top of the stack into the variable `name`, replacing any earlier value.
+    fn store_variable(&mut self, name: &str) {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            self.error = Some(format!("invalid variable name: {name}"));
+            return;
+        }
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo_top(1);
+        let value = self.stack.pop().unwrap();
+        let before = self.variables.insert(name.to_string(), value);
+        self.note_undo(SideChange::Variable(name.to_string(), before, Some(value)));
+    }
+
+    fn recall_variable(&mut self, name: &str) {
+        match self.variables.get(name) {
+            Some(&value) => self.push_number(value),
+            None => self.error = Some(format!("unknown variable: {name}")),
+        }
+    }
+
+    /// Defines a formula such as `area = *`, working out how many values it needs.
+    fn define_formula(&mut self, name: &str, body: &str) {
+        if name.is_empty() || name.contains(char::is_whitespace) || name.parse::<f64>().is_ok() {
+            self.error = Some(format!("invalid formula name: {name}"));
+            return;
+        }
+        if is_known_command(name) {
+            self.error = Some(format!("{name} is already a command"));
+            return;
+        }
+        let Some(arity) = formula_arity(body) else {
+            self.error = Some(format!(
+                "formula bodies may only use numbers and fixed-arity commands: {body}"
+            ));
+            return;
+        };
+        self.status = Some(format!(
+            "{} = {}",
+            format_operation(name, Arity::Fixed(arity)),
+            body
+        ));
+        self.formulas.insert(
+            name.to_string(),
+            Formula {
+                body: body.to_string(),
+                arity,
+            },
+        );
+    }
+
+    fn apply_formula(&mut self, body: &str, arity: usize) {
+        if !self.require_values(arity) {
+            return;
+        }
+        let before = self.stack.clone();
+        self.run_as_one_step(before, body.split_whitespace());
+    }
+
+    /// Runs several commands as one undoable change from `before`, which is restored if any of
+    /// them fails.
+    fn run_as_one_step<'a>(
+        &mut self,
+        before: Vec<f64>,
+        commands: impl IntoIterator<Item = &'a str>,
+    ) -> bool {
+        let record_undo = std::mem::replace(&mut self.record_undo, false);
+        let pinned = self.pinned.clone();
+        let variables = self.variables.clone();
+        let mut running = true;
+        for command in commands {
+            running = self.process_token(command);
+            if !running || self.error.is_some() {
+                break;
+            }
+        }
+        self.record_undo = record_undo;
+        if self.error.is_some() {
+            self.stack = before;
+            self.pinned = pinned;
+            self.variables = variables;
+            return running;
+        }
+        // Snapshot the stack from before the whole step
+        let after = std::mem::replace(&mut self.stack, before);
+        self.push_undo();
+        self.stack = after;
+        if self.pinned != pinned {
+            self.note_undo(SideChange::Pinned(pinned, self.pinned.clone()));
+        }
+        let mut names: Vec<&String> = variables.keys().chain(self.variables.keys()).collect();
+        names.sort();
+        names.dedup();
+        let changes: Vec<SideChange> = names
+            .into_iter()
+            .map(|name| (name, variables.get(name), self.variables.get(name)))
+            .filter(|(_, before, after)| before != after)
+            .map(|(name, before, after)| {
+                SideChange::Variable(name.clone(), before.copied(), after.copied())
+            })
+            .collect();
+        for change in changes {
+            self.note_undo(change);
+        }
+        running
+    }
+
+    /// Replaces the top of the stack with its factorial.
+    ///
+    /// Whole numbers use an exact product, with the sign dropped so `-3 !` gives 6. Other
+    /// values use Γ(x + 1), so `0.5 !` gives √π/2. Past 170! the result is infinity, as either
+    /// would overflow anyway.
+    fn perform_factorial(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+
+        self.push_undo_top(1);
+        let x = self.stack.pop().unwrap();
+        let result = if x.fract() == 0.0 {
+            if x.abs() > MAX_FACTORIAL {
+                f64::INFINITY
+            } else {
+                (1..=x.abs() as u64).map(|i| i as f64).product()
+            }
+        } else if x > MAX_FACTORIAL + 1.0 {
+            // Every non-integer past 171 overflows, and gamma would lose track of it as NaN
+            f64::INFINITY
+        } else {
+            gamma(x + 1.0)
+        };
+        self.stack.push(result);
+    }
+
+    /// Checks the stack holds at least `count` values, setting an underflow error if not.
+    fn require_values(&mut self, count: usize) -> bool {
+        if self.stack.len() >= count {
+            return true;
+        }
+        let noun = if count == 1 { "value" } else { "values" };
+        self.error = Some(format!("need {count} {noun}, have {}", self.stack.len()));
+        false
+    }
+
+    /// Reads the top of the stack as a count, setting an error unless it is a non-negative
+    /// integer.
+    fn top_as_count(&mut self) -> Option<usize> {
+        if !self.require_values(1) {
+            return None;
+        }
+        let top = self.stack[self.stack.len() - 1];
+        if top < 0.0 || top.fract() != 0.0 {
+            self.error = Some(format!("expected a non-negative integer count, got {top}"));
+            return None;
+        }
+        Some(top as usize)
+    }
+
+    /// Pops a length n and combines the two n-element vectors beneath it element-wise.
+    ///
+    /// The first vector sits below the second, so `1 2 3 4 5 6 3 vadd` leaves `5 7 9`.
+    fn perform_vector_operation(&mut self, operation: fn(f64, f64) -> f64) {
+        let Some(n) = self.top_as_count() else {
+            return;
+        };
+        let needed = n.saturating_mul(2).saturating_add(1);
+        if !self.require_values(needed) {
+            return;
+        }
+
+        self.push_undo_top(needed);
+        self.stack.pop();
+        let second = self.stack.split_off(self.stack.len() - n);
+        let first = self.stack.split_off(self.stack.len() - n);
+        self.stack
+            .extend(first.iter().zip(&second).map(|(a, b)| operation(*a, *b)));
+    }
+
+    /// Replaces the top six values, `ax ay az bx by bz`, with the three components of a × b.
+    fn perform_cross_product(&mut self) {
+        if !self.require_values(6) {
+            return;
+        }
+
+        self.push_undo_top(6);
+        let b = self.stack.split_off(self.stack.len() - 3);
+        let a = self.stack.split_off(self.stack.len() - 3);
+        self.stack.push(a[1] * b[2] - a[2] * b[1]);
+        self.stack.push(a[2] * b[0] - a[0] * b[2]);
+        self.stack.push(a[0] * b[1] - a[1] * b[0]);
+    }
+
+    /// Pops a length n and replaces the top n values with their Euclidean norm.
+    fn perform_norm(&mut self) {
+        let Some(n) = self.top_as_count() else {
+            return;
+        };
+        let needed = n.saturating_add(1);
+        if !self.require_values(needed) {
+            return;
+        }
+
+        self.push_undo_top(needed);
+        self.stack.pop();
+        let vector = self.stack.split_off(self.stack.len() - n);
+        let norm = vector.iter().map(|x| x * x).sum::<f64>().sqrt();
+        self.stack.push(norm);
+    }
+
+    /// Outputs the top of the stack without consuming it.
+    fn print_top(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        let top = self.stack[self.stack.len() - 1];
+        let text = self.format_value(top);
+        match self.output {
+            Output::Status => self.status = Some(text),
+            Output::Stdout => println!("{text}"),
+        }
+    }
+
+    /// Pops an expected value and checks the new top matches it, for self-checking scripts.
+    fn perform_assert(&mut self) {
+        if !self.require_values(2) {
+            return;
+        }
+        let expected = self.stack[self.stack.len() - 1];
+        let actual = self.stack[self.stack.len() - 2];
+        if !approx_equal(actual, expected) {
+            self.error = Some(format!(
+                "assertion failed: expected {expected}, got {actual}"
+            ));
+            return;
+        }
+        self.perform_drop();
+    }
+
+    fn save_baseline(&mut self) {
+        self.status = Some(format!("baseline saved ({} values)", self.stack.len()));
+        self.baseline = Some(self.stack.clone());
+    }
+
+    /// Reports whether the stack still matches the saved baseline, allowing for rounding noise.
+    fn check_baseline(&mut self) {
+        match &self.baseline {
+            None => self.error = Some(String::from("no baseline saved")),
+            Some(baseline) if stacks_approx_equal(baseline, &self.stack) => {
+                self.status = Some(String::from("baseline: pass"));
+            }
+            Some(baseline) => {
+                self.error = Some(format!(
+                    "baseline: fail, expected {baseline:?}, got {:?}",
+                    self.stack
+                ));
+            }
+        }
+    }
+
+    /// Combines the whole stack into one value with a binary operator, bottom to top, so
+    /// `10 3 2 reduce -` gives 5.
+    fn perform_reduce(&mut self, operator: &str) {
+        let Some((_, operation)) = BINARY_OPERATORS.iter().find(|(name, _)| *name == operator)
+        else {
+            self.error = Some(format!("reduce needs a binary operator, got {operator}"));
+            return;
+        };
+        if !self.require_values(1) {
+            return;
+        }
+
+        self.push_undo();
+        if self.animate_folds {
+            self.fold_frames
+                .extend(fold_states(&self.stack, *operation).take(MAX_FOLD_FRAMES));
+        }
+        let (&first, rest) = self.stack.split_first().unwrap();
+        let result = rest
+            .iter()
+            .fold(first, |total, value| operation(total, *value));
+        self.stack = vec![result];
+    }
+
+    /// Pops a threshold and pushes how many of the values beneath it satisfy `predicate`.
+    fn perform_count(&mut self, predicate: fn(f64, f64) -> bool) {
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo_top(1);
+        let threshold = self.stack.pop().unwrap();
+        let count = self
+            .stack
+            .iter()
+            .filter(|value| predicate(**value, threshold))
+            .count();
+        self.stack.push(count as f64);
+    }
+
+    /// Pops a threshold and removes every value that doesn't exceed it, keeping the rest in order.
+    fn perform_filter_gt(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo();
+        let threshold = self.stack.pop().unwrap();
+        self.stack.retain(|value| *value > threshold);
+    }
+
+    /// Pushes the position of the largest value, counted from the top like the stack labels,
+    /// so 0 is the top. Ties go to the value nearest the top.
+    fn push_argmax(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        let mut best = 0;
+        for (index, value) in self.stack.iter().rev().enumerate() {
+            if *value > self.stack[self.stack.len() - 1 - best] {
+                best = index;
+            }
+        }
+        self.push_number(best as f64);
+    }
+
+    /// Divides every value by the largest one, so the maximum becomes 1. A largest value of 0
+    /// or below is refused, as dividing by it couldn't do that: `-2 -4` would become `1 2`.
+    fn perform_normalize_max(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        let max = self.stack.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        if max <= 0.0 {
+            self.error = Some(format!(
+                "normalizemax needs a positive largest value, got {}",
+                self.format_value(max)
+            ));
+            return;
+        }
+        self.push_undo();
+        for value in &mut self.stack {
+            *value /= max;
+        }
+    }
+
+    /// Rounds the top to the nearest i64, saturating at the ends of its range and saying so.
+    fn perform_to_int(&mut self) {
+        let mut saturated = None;
+        self.perform_single_operand_operation(|a| {
+            let rounded = a.round();
+            // `as` saturates, and sends NaN to 0
+            let int = rounded as i64;
+            if !(i64::MIN as f64..i64::MAX as f64).contains(&rounded) {
+                saturated = Some(a);
+            }
+            int as f64
+        });
+        if let Some(value) = saturated {
+            let int = self.stack[self.stack.len() - 1] as i64;
+            self.status = Some(format!(
+                "{} is out of i64 range, saturated to {int}",
+                self.format_value(value)
+            ));
+        }
+    }
+
+    /// Replaces the whole stack with a single value worked out from all of it.
+    fn collapse_stack(&mut self, aggregate: fn(&[f64]) -> f64) {
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo();
+        let result = aggregate(&self.stack);
+        self.stack = vec![result];
+    }
+
+    /// Replaces the whole stack with its harmonic mean, n / Σ(1/x).
+    fn perform_harmonic_mean(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        if self.stack.contains(&0.0) {
+            self.error = Some(String::from("harmean is undefined when a value is 0"));
+            return;
+        }
+        self.push_undo();
+        let reciprocal_sum: f64 = self.stack.iter().map(|value| 1.0 / value).sum();
+        let mean = self.stack.len() as f64 / reciprocal_sum;
+        self.stack = vec![mean];
+    }
+
+    /// Replaces `start end` with every whole step from start to end, inclusive.
+    fn perform_range(&mut self) {
+        if !self.require_values(2) {
+            return;
+        }
+        let end = self.stack[self.stack.len() - 1];
+        let start = self.stack[self.stack.len() - 2];
+        let count = (end - start).abs().floor() + 1.0;
+        if !count.is_finite() || count > ITERATION_BUDGET as f64 {
+            self.error = Some(format!(
+                "range would push {count} values, the limit is {ITERATION_BUDGET}"
+            ));
+            return;
+        }
+
+        self.push_undo_top(2);
+        self.stack.truncate(self.stack.len() - 2);
+        let step = if end < start { -1.0 } else { 1.0 };
+        for i in 0..count as usize {
+            self.stack.push(start + step * i as f64);
+        }
+    }
+
+    /// Replaces `value n` with the first n continued-fraction coefficients of value, stopping
+    /// early once the expansion terminates.
+    fn perform_continued_fraction(&mut self) {
+        if !self.require_values(2) {
+            return;
+        }
+        let Some(n) = self.top_as_count() else {
+            return;
+        };
+        if n > ITERATION_BUDGET {
+            self.error = Some(format!(
+                "cfrac would push {n} values, the limit is {ITERATION_BUDGET}"
+            ));
+            return;
+        }
+        let mut value = self.stack[self.stack.len() - 2];
+        if !value.is_finite() {
+            self.error = Some(format!("cannot expand {value} as a continued fraction"));
+            return;
+        }
+
+        self.push_undo_top(2);
+        self.stack.truncate(self.stack.len() - 2);
+        for _ in 0..n {
+            let coefficient = value.floor();
+            self.stack.push(coefficient);
+            let remainder = value - coefficient;
+            if remainder < FLOAT_TOLERANCE {
+                break;
+            }
+            value = 1.0 / remainder;
+        }
+    }
+
+    fn perform_swap(&mut self) {
+        if !self.require_values(2) {
+            return;
+        }
+        self.push_undo_top(2);
+        let b = self.stack.pop().unwrap();
+        let a = self.stack.pop().unwrap();
+        self.stack.push(b);
+        self.stack.push(a);
+    }
+
+    /// Moves the third value from the top up to the top (a b c -> b c a).
+    fn perform_rot(&mut self) {
+        if !self.require_values(3) {
+            return;
+        }
+        self.push_undo_top(3);
+        let len = self.stack.len();
+        self.stack[len - 3..].rotate_left(1);
+    }
+
+    /// Copies the second value from the top onto the top (a b -> a b a).
+    fn perform_over(&mut self) {
+        if !self.require_values(2) {
+            return;
+        }
+        self.push_undo_top(0);
+        self.stack.push(self.stack[self.stack.len() - 2]);
+    }
+
+    /// Swaps the top pair of values with the pair beneath it (a b c d -> c d a b).
+    fn perform_swap2(&mut self) {
+        if !self.require_values(4) {
+            return;
+        }
+        self.push_undo_top(4);
+        let len = self.stack.len();
+        self.stack[len - 4..].rotate_left(2);
+    }
+
+    /// Pops a count n and moves the n-th value from the top up to the top, shifting the values
+    /// above it down one place (1 2 3 4 3 rolln -> 1 3 4 2).
+    fn perform_roll_n(&mut self) {
+        let Some(n) = self.top_as_count() else {
+            return;
+        };
+        let needed = n.saturating_add(1);
+        if !self.require_values(needed) {
+            return;
+        }
+        self.push_undo_top(needed);
+        self.stack.pop();
+        let len = self.stack.len();
+        if n > 0 {
+            self.stack[len - n..].rotate_left(1);
+        }
+    }
+
+    /// Pops an index n and swaps the values n and n + 1 places below the top, so `0 swapat`
+    /// behaves like `swap`.
+    fn perform_swap_at(&mut self) {
+        let Some(n) = self.top_as_count() else {
+            return;
+        };
+        let needed = n.saturating_add(3);
+        if !self.require_values(needed) {
+            return;
+        }
+        self.push_undo_top(needed);
+        self.stack.pop();
+        let len = self.stack.len();
+        self.stack.swap(len - 1 - n, len - 2 - n);
+    }
+
+    /// Pops a depth n and copies the value n places below the top onto the top, so `0 pick`
+    /// behaves like `clone` and `1 pick` like `over`.
+    fn perform_pick(&mut self) {
+        let Some(n) = self.top_as_count() else {
+            return;
+        };
+        if !self.require_values(n.saturating_add(2)) {
+            return;
+        }
+        self.push_undo_top(1);
+        self.stack.pop();
+        self.stack.push(self.stack[self.stack.len() - 1 - n]);
+    }
+
+    /// Pops a depth n and moves the value n places below the top up to the top, so `1 roll`
+    /// behaves like `swap` and `2 roll` like `rot`.
+    fn perform_roll(&mut self) {
+        let Some(n) = self.top_as_count() else {
+            return;
+        };
+        if !self.require_values(n.saturating_add(2)) {
+            return;
+        }
+        self.push_undo_top(n + 2);
+        self.stack.pop();
+        let value = self.stack.remove(self.stack.len() - 1 - n);
+        self.stack.push(value);
+    }
+
+    fn perform_clear(&mut self) {
+        self.push_undo();
+        self.stack.clear();
+    }
+
+    /// Removes every value except the top one.
+    fn perform_keep_top(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo();
+        self.stack.drain(..self.stack.len() - 1);
+    }
+
+    fn perform_drop(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo_top(1);
+        self.stack.pop().unwrap();
+    }
+
+    /// Removes values equal to the one below them, so runs of repeats collapse to one. Values
+    /// repeated further apart are kept, as in `[1, 2, 1]`.
+    fn perform_dedup(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo();
+        self.stack
+            .dedup_by(|value, below| approx_equal(*value, *below));
+    }
+
+    /// Replaces the stack with the length of each run of equal neighbouring values, bottom run
+    /// first, so `[1, 1, 2]` becomes `[2, 1]`. The values themselves are dropped.
+    fn perform_run_length(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo();
+        let mut counts: Vec<f64> = Vec::new();
+        let mut previous: Option<f64> = None;
+        for &value in &self.stack {
+            match (previous, counts.last_mut()) {
+                (Some(below), Some(count)) if approx_equal(value, below) => *count += 1.0,
+                _ => counts.push(1.0),
+            }
+            previous = Some(value);
+        }
+        self.stack = counts;
+    }
+
+    /// Moves the top of the stack to the pinned values, where stack commands can't touch it.
+    fn perform_pin(&mut self) {
+        if !self.require_values(1) {
+            return;
+        }
+        self.push_undo_top(1);
+        let before = self.pinned.clone();
+        let value = self.stack.pop().unwrap();
+        self.pinned.push(value);
+        self.note_undo(SideChange::Pinned(before, self.pinned.clone()));
+    }
+
+    /// Drops the most recently pinned value.
+    fn perform_unpin(&mut self) {
+        if self.pinned.is_empty() {
+            self.error = Some(String::from("nothing is pinned"));
+            return;
+        }
+        self.push_undo_top(0);
+        let before = self.pinned.clone();
+        self.pinned.pop();
+        self.note_undo(SideChange::Pinned(before, self.pinned.clone()));
+    }
+
+    fn pinned_text(&self) -> String {
+        if self.pinned.is_empty() {
+            return String::new();
+        }
+        let values: Vec<String> = self
+            .pinned
+            .iter()
+            .map(|value| self.format_value(*value))
+            .collect();
+        format!("pinned: {}", values.join(", "))
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Population standard deviation, treating the values as the whole population.
+fn standard_deviation(values: &[f64]) -> f64 {
+    let average = mean(values);
+    let squared_deviations: Vec<f64> = values
+        .iter()
+        .map(|value| (value - average).powi(2))
+        .collect();
+    mean(&squared_deviations).sqrt()
+}
+
+/// Compares floats with a tolerance relative to their size, so rounding noise doesn't count.
+fn approx_equal(a: f64, b: f64) -> bool {
+    a == b || (a - b).abs() <= FLOAT_TOLERANCE * a.abs().max(b.abs()).max(1.0)
+}
+
+/// Applies the trig command `command` to `value`, returning its angle unit and the result.
+fn trig_preview(command: &str, value: f64, mode: AngleMode) -> Option<(&'static str, f64)> {
+    TRIG_FUNCTIONS
+        .iter()
+        .find(|(name, _, _)| *name == command)
+        .map(|&(_, angle, function)| apply_trig(angle, function, mode, value))
+}
+
+/// Applies a radian trig function with its angle converted to or from `mode`, returning the
+/// unit the angle ended up in and the result.
+fn apply_trig(
+    angle: TrigAngle,
+    function: UnaryOperation,
+    mode: AngleMode,
+    value: f64,
+) -> (&'static str, f64) {
+    match angle {
+        TrigAngle::Input => (mode.name(), function(mode.to_radians(value))),
+        TrigAngle::Output => (mode.name(), mode.convert_from_radians(function(value))),
+        TrigAngle::Degrees => ("degrees", function(value)),
+    }
+}
+
+/// Every stack a left fold of `values` passes through, from the untouched values to the
+/// single result, with the running total at the bottom. Each is only built when asked for.
+fn fold_states(values: &[f64], operation: BinaryOperation) -> impl Iterator<Item = Vec<f64>> + '_ {
+    let first = values.first().copied().unwrap_or_default();
+    let steps = values
+        .iter()
+        .enumerate()
+        .skip(1)
+        .scan(first, move |accumulator, (i, value)| {
+            *accumulator = operation(*accumulator, *value);
+            let mut state = vec![*accumulator];
+            state.extend_from_slice(&values[i + 1..]);
+            Some(state)
+        });
+    std::iter::once(values.to_vec()).chain(steps)
+}
+
+fn literal_expression(text: &str) -> Expression {
+    // A leading minus binds like negation, so `-3 ^ 2` gets brackets
+    let precedence = if text.starts_with('-') {
+        3
+    } else {
+        ATOM_PRECEDENCE
+    };
+    (text.to_string(), precedence)
+}
+
+/// Applies one token to a stack of infix expressions, returning false if it isn't an
+/// arithmetic step that infix can show.
+fn infix_step(expressions: &mut Vec<Expression>, token: &str) -> bool {
+    let is_constant =
+        matches!(token, "pi" | "inf") || CONSTANTS.iter().any(|(name, _)| *name == token);
+    let is_number =
+        token.parse::<f64>().is_ok() || matches!(parse_radix_literal(token), Some(Ok(_)));
+    if is_number || is_constant {
+        expressions.push(literal_expression(token));
+        return true;
+    }
+    let len = expressions.len();
+    let precedence = match token {
+        "" | "clone" if len > 0 => {
+            expressions.push(expressions[len - 1].clone());
+            return true;
+        }
+        "swap" if len > 1 => {
+            expressions.swap(len - 1, len - 2);
+            return true;
+        }
+        "drop" => return expressions.pop().is_some(),
+        "+" | "-" => 1,
+        "*" | "/" | "%" => 2,
+        "^" => 3,
+        _ => {
+            let is_unary = OPERATIONS
+                .iter()
+                .any(|(group, ops)| *group == "Unary" && ops.iter().any(|(op, _)| *op == token));
+            let Some((operand, operand_precedence)) = expressions.pop().filter(|_| is_unary) else {
+                return false;
+            };
+            let bracketed = if operand_precedence < ATOM_PRECEDENCE {
+                format!("({operand})")
+            } else {
+                operand.clone()
+            };
+            expressions.push(match token {
+                "neg" => (format!("-{bracketed}"), 3),
+                "!" => (format!("{bracketed}!"), ATOM_PRECEDENCE),
+                _ => (format!("{token}({operand})"), ATOM_PRECEDENCE),
+            });
+            return true;
+        }
+    };
+    if len < 2 {
+        return false;
+    }
+    let second = expressions.pop().unwrap();
+    let first = expressions.pop().unwrap();
+    // `a b ^` raises b to the power a, so the operands swap sides
+    let (left, right) = if token == "^" {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    let left_brackets = left.1 < precedence || (token == "^" && left.1 == precedence);
+    let right_brackets =
+        right.1 < precedence || (right.1 == precedence && matches!(token, "-" | "/" | "%"));
+    let wrap = |(text, _): Expression, brackets: bool| {
+        if brackets {
+            format!("({text})")
+        } else {
+            text
+        }
+    };
+    expressions.push((
+        format!(
+            "{} {token} {}",
+            wrap(left, left_brackets),
+            wrap(right, right_brackets)
+        ),
+        precedence,
+    ));
+    true
+}
+
+/// The values as a row of block characters, scaled from the smallest to the largest. Values
+/// that aren't finite are left blank.
+fn sparkline(values: &[f64]) -> String {
+    let finite = values.iter().copied().filter(|value| value.is_finite());
+    let min = finite.clone().fold(f64::INFINITY, f64::min);
+    let max = finite.fold(f64::NEG_INFINITY, f64::max);
+    let top = (SPARK_LEVELS.len() - 1) as f64;
+    values
+        .iter()
+        .map(|value| {
+            if !value.is_finite() {
+                ' '
+            } else if max > min {
+                SPARK_LEVELS[((value - min) / (max - min) * top).round() as usize]
+            } else {
+                // A flat line sits in the middle
+                SPARK_LEVELS[SPARK_LEVELS.len() / 2 - 1]
+            }
+        })
+        .collect()
+}
+
+/// Bar heights for `values`, scaled so the largest magnitude reaches `HISTOGRAM_SCALE`, each
+/// with whether the value is negative. Values that aren't finite get no bar.
+fn histogram_bars(values: &[f64]) -> Vec<(u64, bool)> {
+    let largest = values
+        .iter()
+        .filter(|value| value.is_finite())
+        .fold(0.0, |largest: f64, value| largest.max(value.abs()));
+    values
+        .iter()
+        .map(|value| {
+            let height = if value.is_finite() && largest > 0.0 {
+                (value.abs() / largest * HISTOGRAM_SCALE as f64).round() as u64
+            } else {
+                0
+            };
+            (height, value.is_sign_negative() && *value != 0.0)
+        })
+        .collect()
+}
+
+/// Points to plot for `values`, as (stack position, value), leaving out values that aren't
+/// finite.
+fn plot_points(values: &[f64]) -> Vec<(f64, f64)> {
+    values
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| value.is_finite())
+        .map(|(i, value)| (i as f64, *value))
+        .collect()
+}
+
+/// The x and y ranges that fit every point, widened where they would be empty.
+fn plot_bounds(points: &[(f64, f64)]) -> ([f64; 2], [f64; 2]) {
+    let widen = |[low, high]: [f64; 2]| {
+        if low < high {
+            [low, high]
+        } else {
+            [low - 1.0, high + 1.0]
+        }
+    };
+    if points.is_empty() {
+        return ([0.0, 1.0], [0.0, 1.0]);
+    }
+    let x = points
+        .iter()
+        .fold([f64::INFINITY, f64::NEG_INFINITY], |[low, high], (x, _)| {
+            [low.min(*x), high.max(*x)]
+        });
+    let y = points
+        .iter()
+        .fold([f64::INFINITY, f64::NEG_INFINITY], |[low, high], (_, y)| {
+            [low.min(*y), high.max(*y)]
+        });
+    (widen(x), widen(y))
+}
+
+/// Splits an f64 into its IEEE 754 sign bit, biased exponent and 52-bit mantissa.
+fn decompose_bits(value: f64) -> (u64, u64, u64) {
+    let bits = value.to_bits();
+    (bits >> 63, (bits >> 52) & 0x7ff, bits & ((1 << 52) - 1))
+}
+
+/// Whether a line of input wipes out more than the values it operates on.
+fn is_destructive(input: &str) -> bool {
+    split_commands(input).into_iter().any(|command| {
+        matches!(command, "clear" | "keeptop" | "filtergt") || command.starts_with("set ")
+    })
+}
+
+/// Compares stacks value by value with `approx_equal`.
+fn stacks_approx_equal(a: &[f64], b: &[f64]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| approx_equal(*x, *y))
+}
+
+/// Moves the stack panel's scroll offset a page down (deeper) or up, staying within the stack.
+fn page_offset(offset: usize, page_size: usize, len: usize, down: bool) -> usize {
+    let max_offset = len.saturating_sub(page_size);
+    if down {
+        offset.saturating_add(page_size).min(max_offset)
+    } else {
+        offset.min(max_offset).saturating_sub(page_size)
+    }
+}
+
+/// Positions in `after`, counted from the bottom, that are new or differ from `before`.
+fn changed_positions(before: &[f64], after: &[f64]) -> Vec<usize> {
+    after
+        .iter()
+        .enumerate()
+        .filter(|(i, value)| before.get(*i).map(|old| old.to_bits()) != Some(value.to_bits()))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Reads a history file, one input per line, keeping only the newest `MAX_HISTORY`.
+fn parse_history(text: &str) -> Vec<String> {
+    let lines: Vec<String> = text
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect();
+    let skip = lines.len().saturating_sub(MAX_HISTORY);
+    lines.into_iter().skip(skip).collect()
+}
+
+fn format_history(history: &[String]) -> String {
+    history.iter().map(|input| format!("{input}\n")).collect()
+}
+
+/// Whether `value` rounds to a whole number an f64 holds exactly, i.e. one within ±2^53.
+fn is_exact_integer(value: f64) -> bool {
+    value.is_finite() && value.round().abs() <= MAX_EXACT_INTEGER
+}
+
+/// Computes base^exponent mod modulus by repeated squaring, so intermediate values never
+/// exceed modulus². The result always lies in `0..|modulus|`. Every operand must pass
+/// `is_exact_integer`, which keeps modulus² well inside i128.
+fn modular_power(base: f64, exponent: f64, modulus: f64) -> f64 {
+    let modulus = modulus.round().abs() as i128;
+    let mut base = (base.round() as i128).rem_euclid(modulus);
+    let mut exponent = exponent.round() as u64;
+    let mut result = 1 % modulus;
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * base % modulus;
+        }
+        base = base * base % modulus;
+        exponent >>= 1;
+    }
+    result as f64
+}
+
+/// Finds the inverse of `value` modulo `modulus` with the extended Euclidean algorithm, or
+/// `None` when they share a factor.
+fn modular_inverse(value: i128, modulus: i128) -> Option<i128> {
+    let modulus = modulus.abs();
+    if modulus == 0 {
+        return None;
+    }
+    let (mut old_r, mut r) = (value.rem_euclid(modulus), modulus);
+    let (mut old_s, mut s) = (1, 0);
+    while r != 0 {
+        let quotient = old_r / r;
+        (old_r, r) = (r, old_r - quotient * r);
+        (old_s, s) = (s, old_s - quotient * s);
+    }
+    (old_r == 1).then(|| old_s.rem_euclid(modulus))
+}
+
+/// `value` as an `i64`, or `None` if it has a fractional part or doesn't fit.
+fn exact_i64(value: f64) -> Option<i64> {
+    let in_range = (i64::MIN as f64..i64::MAX as f64).contains(&value);
+    (in_range && value.fract() == 0.0).then_some(value as i64)
+}
+
+/// Greatest common divisor by the Euclidean algorithm. `gcd(0, 0)` is 0.
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// Least common multiple, 0 when either value is 0.
+fn lcm(a: u128, b: u128) -> u128 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    (a / gcd(a, b)).saturating_mul(b)
+}
+
+/// The gamma function, by the Lanczos approximation (g = 7), accurate to about 15 digits.
+fn gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        // Reflection formula, since the approximation only holds for the right half-plane
+        return f64::consts::PI / ((f64::consts::PI * x).sin() * gamma(1.0 - x));
+    }
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let series = COEFFICIENTS[1..]
+        .iter()
+        .enumerate()
+        .fold(COEFFICIENTS[0], |sum, (i, c)| {
+            sum + c / (x + i as f64 + 1.0)
+        });
+    // t^(x + 0.5) alone overflows near the top of the range, so apply it in two halves
+    let half_power = t.powf((x + 0.5) / 2.0);
+    (2.0 * f64::consts::PI).sqrt() * half_power * (-t).exp() * half_power * series
+}
+
+/// Counts the fractional digits in the shortest decimal representation of `value`.
+fn decimal_places(value: f64) -> usize {
+    value
+        .to_string()
+        .split_once('.')
+        .map_or(0, |(_, fraction)| fraction.len())
+}
+
+fn format_with_precision(value: f64, precision: usize) -> String {
+    format!("{value:.precision$}")
+}
+
+/// SI prefixes from 10^-24 to 10^24, one per power of a thousand
+const SI_PREFIXES: [&str; 17] = [
+    "y", "z", "a", "f", "p", "n", "µ", "m", "", "k", "M", "G", "T", "P", "E", "Z", "Y",
+];
+
+/// Formats `value` with an exponent that is a multiple of three, written as an SI prefix.
+fn format_engineering(value: f64) -> String {
+    if value == 0.0 || !value.is_finite() {
+        return value.to_string();
+    }
+    let lowest = -(SI_PREFIXES.len() as i32 / 2);
+    let highest = SI_PREFIXES.len() as i32 / 2;
+    let mut group = (value.abs().log10() / 3.0).floor() as i32;
+    // Rounding off float noise can carry the mantissa up to the next group, e.g. 999.9999999999
+    let mut mantissa = round_to_significant_figures(value / 1000f64.powi(group), 12.0);
+    if mantissa.abs() >= 1000.0 {
+        group += 1;
+        mantissa /= 1000.0;
+    }
+    let clamped = group.clamp(lowest, highest);
+    if clamped != group {
+        mantissa = round_to_significant_figures(value / 1000f64.powi(clamped), 12.0);
+    }
+    format!("{mantissa}{}", SI_PREFIXES[(clamped - lowest) as usize])
+}
+
+/// Rounds `value` to two decimal places, halves away from zero. The rounding works in whole
+/// cents on the shortest decimal that reads back as `value`, so 2.675 gives 2.68 even though the
+/// nearest float is a little below it. Values too large to have cents are left alone.
+fn round_to_cents(value: f64) -> f64 {
+    if !value.is_finite() || value.abs() >= 1e15 {
+        return value;
+    }
+    let text = value.abs().to_string();
+    let (whole, fraction) = text.split_once('.').unwrap_or((&text, ""));
+    let digit = |index| {
+        fraction
+            .as_bytes()
+            .get(index)
+            .map_or(0, |digit| i64::from(digit - b'0'))
+    };
+    let mut cents = whole.parse::<i64>().unwrap() * 100 + digit(0) * 10 + digit(1);
+    if digit(2) >= 5 {
+        cents += 1;
+    }
+    (cents as f64 / 100.0).copysign(value)
+}
+
+/// Rounds `value` to `figures` significant figures, keeping at least one. An f64 never has
+/// more than 17, so asking for that many leaves `value` as it is, as does a value so small its
+/// scale would overflow.
+fn round_to_significant_figures(value: f64, figures: f64) -> f64 {
+    if value == 0.0 || !value.is_finite() || figures.round() >= 17.0 {
+        return value;
+    }
+    let magnitude = value.abs().log10().floor();
+    let exponent = figures.round().max(1.0) - 1.0 - magnitude;
+    let scale = 10f64.powf(exponent.abs());
+    if !scale.is_finite() {
+        return value;
+    }
+    // Divide by the larger power of ten rather than multiplying by its inexact reciprocal
+    if exponent >= 0.0 {
+        (value * scale).round() / scale
+    } else {
+        (value / scale).round() * scale
+    }
+}
+
+/// Labels a command in the operations guide with how many values it needs, e.g. "+ (2)".
+fn format_operation(name: &str, arity: Arity) -> String {
+    match arity {
+        Arity::Fixed(0) => name.to_string(),
+        Arity::Fixed(count) => format!("{name} ({count})"),
+        Arity::Counted => format!("{name} (n)"),
+        Arity::All => format!("{name} (all)"),
+    }
+}
+
+/// Parses an integer written as `0xff`, `0o17` or `0b1010`, with an optional leading minus.
+/// `None` if the input has none of those prefixes.
+fn parse_radix_literal(input: &str) -> Option<Result<f64, String>> {
+    let (negative, unsigned) = match input.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, input),
+    };
+    let (radix, name) = match unsigned.get(..2)? {
+        "0x" => (16, "hexadecimal"),
+        "0o" => (8, "octal"),
+        "0b" => (2, "binary"),
+        _ => return None,
+    };
+    let digits = &unsigned[2..];
+    // from_str_radix takes a sign of its own, which shouldn't follow the prefix
+    if digits.starts_with(['+', '-']) {
+        return Some(Err(format!("invalid {name} literal: {input}")));
+    }
+    Some(
+        i64::from_str_radix(digits, radix)
+            .map(|value| if negative { -value } else { value })
+            .map(|value| value as f64)
+            .map_err(|_| format!("invalid {name} literal: {input}")),
+    )
+}
+
+/// Splits input like `+5` into an operator and the operand to apply it with.
+///
+/// `-` is left out so that negative numbers can still be typed; use `+-3` to subtract 3.
+fn combined_operation(input: &str) -> Option<(BinaryOperation, f64)> {
+    let (symbol, operand) = input.split_at(input.chars().next()?.len_utf8());
+    let operand = operand.parse::<f64>().ok()?;
+    BINARY_OPERATORS
+        .iter()
+        .find(|(name, _)| *name == symbol && symbol != "-")
+        .map(|(_, operation)| (*operation, operand))
+}
+
+/// The fewest stack values a formula body can run on. `None` if the body uses something
+/// whose needs aren't fixed.
+fn formula_arity(body: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut needed = 0;
+    for token in body.split_whitespace() {
+        let (consumed, results) =
+            if token.parse::<f64>().is_ok() || matches!(parse_radix_literal(token), Some(Ok(_))) {
+                (0, 1)
+            } else if token == "quit" {
+                return None;
+            } else {
+                match command_arity(token)? {
+                    Arity::Fixed(count) => (count, command_results(token)),
+                    Arity::Counted | Arity::All => return None,
+                }
+            };
+        if depth < consumed {
+            needed += consumed - depth;
+            depth = consumed;
+        }
+        depth = depth - consumed + results;
+    }
+    (needed > 0 || depth > 0).then_some(needed)
+}
+
+/// How many values a fixed-arity command leaves in place of the ones it consumes.
+fn command_results(command: &str) -> usize {
+    match command {
+        "swap" | "clone" | "decimals" | "dupbottom" => 2,
+        "cross" | "rot" | "over" => 3,
+        "swap2" => 4,
+        "drop" | "clear" | "pin" => 0,
+        _ if command_arity(command) == Some(Arity::Fixed(0)) => usize::from(
+            matches!(command, "inf" | "pi") || CONSTANTS.iter().any(|(name, _)| *name == command),
+        ),
+        _ => 1,
+    }
+}
+
+/// How many values a command from the operations guide consumes.
+fn command_arity(command: &str) -> Option<Arity> {
+    OPERATIONS
+        .iter()
+        .flat_map(|(_, ops)| ops.iter())
+        .find(|(op, _)| op.split_whitespace().next() == Some(command))
+        .map(|(_, arity)| *arity)
+        .or_else(|| {
+            TEMPERATURE_CONVERSIONS
+                .iter()
+                .any(|(name, _)| *name == command)
+                .then_some(Arity::Fixed(1))
+        })
+}
+
+/// Whether a command takes the rest of its line as arguments, like `fix 3` or `reduce +`.
+/// These are the ones whose guide entry shows a `<…>` or quoted placeholder; notes such as
+/// `clone (empty)` don't count.
+fn takes_arguments(command: &str) -> bool {
+    OPERATIONS
+        .iter()
+        .flat_map(|(_, ops)| ops.iter())
+        .any(|(op, _)| {
+            op.split_once(' ').is_some_and(|(name, arguments)| {
+                name == command && arguments.starts_with(['<', '"'])
+            })
+        })
+}
+
+/// Splits a line of input into the commands it runs, one per word, except that a command
+/// taking arguments gets the rest of the line, as in `3 4 + sto x`. Formula definitions are
+/// left whole.
+fn split_commands(input: &str) -> Vec<&str> {
+    if input.contains(" = ") {
+        return vec![input];
+    }
+    let mut commands = Vec::new();
+    let mut rest = input.trim_start();
+    while !rest.is_empty() {
+        match rest.split_once(char::is_whitespace) {
+            Some((word, tail)) if !takes_arguments(word) => {
+                commands.push(word);
+                rest = tail.trim_start();
+            }
+            _ => {
+                commands.push(rest);
+                break;
+            }
+        }
+    }
+    if commands.is_empty() {
+        commands.push(input);
+    }
+    commands
+}
+
+/// Every command word, without the arguments shown in the operations guide.
+fn command_names() -> impl Iterator<Item = &'static str> {
+    OPERATIONS
+        .iter()
+        .flat_map(|(_, ops)| ops.iter())
+        .filter_map(|(op, _)| op.split_whitespace().next())
+        .chain(TEMPERATURE_CONVERSIONS.iter().map(|(name, _)| *name))
+        .chain(CONSTANTS.iter().map(|(name, _)| *name))
+}
+
+fn is_known_command(token: &str) -> bool {
+    command_names().any(|name| name == token)
+}
+
+/// Finds the command closest to a mistyped `token`, if any is within an edit per three
+/// characters, up to two. Short tokens are too close to too many commands to guess at.
+fn suggest_command(token: &str) -> Option<&'static str> {
+    let allowed = (token.chars().count() / 3).min(2);
+    command_names()
+        .map(|name| (levenshtein_distance(token, name), name))
+        .filter(|(distance, _)| *distance <= allowed)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+/// Number of single-character insertions, deletions and substitutions to turn `a` into `b`.
+fn levenshtein_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::{
+        changed_positions, decimal_places, decompose_bits, fold_states, format_engineering,
+        format_history, format_operation, format_with_precision, formula_arity, histogram_bars,
+        infix_step, is_known_command, levenshtein_distance, page_offset, parse_history,
+        plot_bounds, plot_points, round_to_significant_figures, sparkline, stacks_approx_equal,
+        suggest_command, trig_preview, AngleMode, App, Arity, Config, InputMode, StackDelta,
+        MAX_FOLD_FRAMES, MAX_HISTORY,
+    };
+
+    mod tui {
+
+        use core::f64;
+
+        use super::{is_known_command, App};
+        #[test]
+        fn cursor_movement_left() {
+            let mut app = App::new();
+            app.input = String::from("hello");
+            app.character_index = 3;
+            app.move_cursor_left();
+            assert_eq!(app.character_index, 2);
+        }
+
+        #[test]
+        fn cursor_movement_right() {
+            let mut app = App::new();
+            app.input = String::from("hello");
+            app.character_index = 1;
+            app.move_cursor_right();
+            assert_eq!(app.character_index, 2);
+        }
+
+        #[test]
+        fn enter_char() {
+            let mut app = App::new();
+            app.enter_char('a');
+            assert_eq!(app.input, "a");
+            assert_eq!(app.character_index, 1);
+        }
+
+        #[test]
+        fn delete_char() {
+            let mut app = App::new();
+            app.input = String::from("hello");
+            app.character_index = 3;
+            app.delete_char();
+            assert_eq!(app.input, "helo");
+            assert_eq!(app.character_index, 2);
+        }
+
+        #[test]
+        fn toggle_input_sign() {
+            let mut app = App::new();
+            app.input = String::from("5");
+            app.character_index = 1;
+            app.toggle_input_sign();
+            assert_eq!(app.input, "-5");
+            assert_eq!(app.character_index, 2);
+
+            app.toggle_input_sign();
+            assert_eq!(app.input, "5");
+            assert_eq!(app.character_index, 1);
+        }
+
+        #[test]
+        fn insert_constant() {
+            let mut app = App::new();
+            app.insert_constant(f64::consts::PI);
+            assert_eq!(app.input, f64::consts::PI.to_string());
+            assert_eq!(app.character_index, app.input.len());
+        }
+
+        #[test]
+        fn repeat_count() {
+            let mut app = App::new();
+            app.push_repeat_digit(1);
+            app.push_repeat_digit(2);
+            assert_eq!(app.repeat_count, Some(12));
+
+            for _ in 0..4 {
+                app.push_repeat_digit(9);
+            }
+            assert_eq!(app.repeat_count, Some(1000));
+        }
+
+        #[test]
+        fn repeated_input() {
+            let mut app = App::new();
+            app.stack = vec![1.0, 2.0, 3.0, 4.0, 5.0];
+            app.push_repeat_digit(3);
+            app.input = String::from("drop");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 2.0]);
+            assert_eq!(app.repeat_count, None);
+
+            app.input = String::from("drop");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+        }
+
+        #[test]
+        fn panicking_operation_is_caught() {
+            let mut app = App::new();
+            app.stack = vec![1.0, 2.0];
+            app.input = String::from("boom");
+            let keep_running = app
+                .catch_panics(|app| {
+                    app.stack.clear();
+                    panic!("index out of bounds")
+                })
+                .unwrap();
+            assert!(keep_running);
+            assert_eq!(app.stack, vec![1.0, 2.0]);
+            assert_eq!(app.input, "");
+            assert_eq!(
+                app.error.as_deref(),
+                Some("internal error: index out of bounds")
+            );
+        }
+
+        #[test]
+        fn panic_restores_undoable_state() {
+            let mut app = App::new();
+            for input in ["1", "2", "3"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            app.catch_panics(|app| {
+                app.perform_pin();
+                app.push_undo();
+                app.variables.insert(String::from("x"), 1.0);
+                panic!("boom")
+            })
+            .unwrap();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
+            assert!(app.pinned.is_empty());
+            assert!(app.variables.is_empty());
+
+            // The next undo steps back over the last real change, not one recorded mid-panic
+            app.undo();
+            assert_eq!(app.stack, vec![1.0, 2.0]);
+        }
+
+        #[test]
+        fn delete_command_or_char() {
+            assert!(is_known_command("sqrt"));
+            assert!(is_known_command("c2f"));
+            assert!(is_known_command("convert"));
+            assert!(!is_known_command("sqr"));
+
+            let mut app = App::new();
+            app.input = String::from("sqr");
+            app.character_index = 3;
+            app.delete_command_or_char();
+            assert_eq!(app.input, "sq");
+
+            app.input = String::from("sqrt");
+            app.character_index = 4;
+            app.delete_command_or_char();
+            assert_eq!(app.input, "");
+            assert_eq!(app.character_index, 0);
+        }
+    }
+
+    #[allow(clippy::approx_constant)]
+    mod process_input {
+
+        use core::f64;
+
+        use super::{App, MAX_FOLD_FRAMES};
+
+        #[test]
+        fn addition() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+
+            app.input = String::from("95.678");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0, 95.678]);
+
+            app.input = String::from("+");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![105.678]);
+        }
+
+        #[test]
+        fn subtraction() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+
+            app.input = String::from("4");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0, 4.0]);
+
+            app.input = String::from("-");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![6.0]);
+        }
+
+        #[test]
+        fn multiplication() {
+            let mut app = App::new();
+            app.input = String::from("2");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.0]);
+
+            app.input = String::from("3");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.0, 3.0]);
+
+            app.input = String::from("*");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![6.0]);
+        }
+
+        #[test]
+        fn division() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+
+            app.input = String::from("2");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0, 2.0]);
+
+            app.input = String::from("/");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0]);
+        }
+
+        #[test]
+        fn modulus() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+
+            app.input = String::from("3");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0, 3.0]);
+
+            app.input = String::from("%");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+        }
+
+        #[test]
+        fn exponentiation() {
+            let mut app = App::new();
+            app.input = String::from("2");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.0]);
+
+            app.input = String::from("3");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.0, 3.0]);
+
+            app.input = String::from("^");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![9.0]);
+        }
+
+        #[test]
+        fn discount() {
+            let mut app = App::new();
+            app.input = String::from("100");
+            app.process_input().unwrap();
+            app.input = String::from("20");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![100.0, 20.0]);
+
+            app.input = String::from("discount");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![80.0]);
+        }
+
+        #[test]
+        fn percent_error() {
+            let mut app = App::new();
+            for input in ["100", "95", "pcterr"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![5.0]);
+
+            for input in ["0", "3", "pcterr"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![5.0, 0.0, 3.0]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn cents() {
+            for (input, expected) in [
+                ("2.675", 2.68),
+                ("1.005", 1.01),
+                ("2.674", 2.67),
+                ("-2.675", -2.68),
+                ("3", 3.0),
+            ] {
+                let mut app = App::new();
+                app.input = String::from(input);
+                app.process_input().unwrap();
+                app.input = String::from("cents");
+                app.process_input().unwrap();
+                assert_eq!(app.stack, vec![expected], "{input}");
+            }
+        }
+
+        #[test]
+        fn markup() {
+            let mut app = App::new();
+            app.input = String::from("100");
+            app.process_input().unwrap();
+            app.input = String::from("8");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![100.0, 8.0]);
+
+            app.input = String::from("markup");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![108.0]);
+        }
+
+        #[test]
+        fn compound_interest() {
+            let mut app = App::new();
+            app.input = String::from("1000");
+            app.process_input().unwrap();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+            app.input = String::from("2");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1000.0, 5.0, 2.0]);
+
+            app.input = String::from("compound");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1102.5]);
+        }
+
+        #[test]
+        fn compound_interest_too_few_operands() {
+            let mut app = App::new();
+            app.input = String::from("1000");
+            app.process_input().unwrap();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+
+            app.input = String::from("compound");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1000.0, 5.0]);
+            assert_eq!(app.error.as_deref(), Some("need 3 values, have 2"));
+        }
+
+        #[test]
+        fn significant_figures() {
+            let mut app = App::new();
+            app.input = String::from("12345");
+            app.process_input().unwrap();
+            app.input = String::from("2");
+            app.process_input().unwrap();
+
+            app.input = String::from("sigfig");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![12000.0]);
+        }
+
+        #[test]
+        fn combined_operator_and_operand() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+
+            app.input = String::from("+5");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![15.0]);
+
+            app.input = String::from("*2");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![30.0]);
+
+            app.input = String::from("+-3");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![27.0]);
+
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![30.0]);
+        }
+
+        #[test]
+        fn minus_number_stays_a_negative_literal() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+
+            app.input = String::from("-3");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0, -3.0]);
+        }
+
+        #[test]
+        fn negation() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+
+            app.input = String::from("neg");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![-10.0]);
+        }
+
+        #[test]
+        fn absolute_value() {
+            let mut app = App::new();
+            app.input = String::from("-5");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![-5.0]);
+
+            app.input = String::from("abs");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0]);
+        }
+
+        #[test]
+        fn square_root() {
+            let mut app = App::new();
+            app.input = String::from("16");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![16.0]);
+
+            app.input = String::from("sqrt");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![4.0]);
+        }
+
+        #[test]
+        fn sine() {
+            let mut app = App::new();
+            app.input = String::from("0");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0]);
+
+            app.input = String::from("sin");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0]);
+        }
+
+        #[test]
+        fn cosine() {
+            let mut app = App::new();
+            app.input = String::from("0");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0]);
+
+            app.input = String::from("cos");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+        }
+
+        #[test]
+        fn tangent() {
+            let mut app = App::new();
+            app.input = String::from("0");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0]);
+
+            app.input = String::from("tan");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0]);
+        }
+
+        #[test]
+        fn arcsine() {
+            let mut app = App::new();
+            app.input = String::from("1");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+
+            app.input = String::from("asin");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.5707963267948966]); // ~π/2
+        }
+
+        #[test]
+        fn arccosine() {
+            let mut app = App::new();
+            app.input = String::from("1");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+
+            app.input = String::from("acos");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0]);
+        }
+
+        #[test]
+        fn arctangent() {
+            let mut app = App::new();
+            app.input = String::from("1");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+
+            app.input = String::from("atan");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.7853981633974483]); // ~π/4
+        }
+
+        #[test]
+        fn several_tokens_per_line() {
+            let mut app = App::new();
+            app.input = String::from("3 4 +");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![7.0]);
+
+            let mut app = App::new();
+            app.input = String::from("2 3 4 + *");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![14.0]);
+
+            app.input = String::from("infix");
+            app.process_input().unwrap();
+            assert_eq!(app.status.as_deref(), Some("2 * (3 + 4)"));
+
+            // The whole line is one undo step, and is rolled back if any token fails
+            app.input = String::from("1 + 2 nope");
+            app.process_input().unwrap();
+            assert!(app.error.is_some());
+            assert_eq!(app.stack, vec![14.0]);
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert!(app.stack.is_empty());
+
+            // Commands with arguments keep the rest of the line
+            app.input = String::from("set 1 2 3");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
+        }
+
+        #[test]
+        fn clone_in_a_line() {
+            let mut app = App::new();
+            app.input = String::from("3 clone *");
+            app.process_input().unwrap();
+            assert_eq!(app.error, None);
+            assert_eq!(app.stack, vec![9.0]);
+        }
+
+        #[test]
+        fn variables() {
+            let mut app = App::new();
+            for input in ["3 4 + sto x", "2", "rcl x", "*"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![14.0]);
+            assert_eq!(app.variables.get("x"), Some(&7.0));
+
+            // Storing again replaces the old value
+            for input in ["sto x", "rcl x", "rcl x"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![14.0, 14.0]);
+
+            app.input = String::from("rcl y");
+            app.process_input().unwrap();
+            assert_eq!(app.error.as_deref(), Some("unknown variable: y"));
+            assert_eq!(app.stack, vec![14.0, 14.0]);
+
+            app.input = String::from("sto");
+            app.process_input().unwrap();
+            assert_eq!(app.error.as_deref(), Some("sto is missing its arguments"));
+        }
+
+        #[test]
+        fn undo_variable() {
+            let mut app = App::new();
+            for input in ["1", "sto x", "undo"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![1.0]);
+            assert_eq!(app.variables.get("x"), None);
+
+            app.input = String::from("redo");
+            app.process_input().unwrap();
+            assert!(app.stack.is_empty());
+            assert_eq!(app.variables.get("x"), Some(&1.0));
+
+            // Undoing a replacement brings back the old value, also from a multi-command line
+            for input in ["2 sto x", "undo"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.variables.get("x"), Some(&1.0));
+        }
+
+        #[test]
+        fn sine_of_degrees() {
+            let mut app = App::new();
+            app.input = String::from("90");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![90.0]);
+
+            app.input = String::from("sindeg");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+        }
+
+        #[test]
+        fn angle_mode() {
+            let mut app = App::new();
+            for input in ["90", "sin"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert!((app.stack[0] - 0.8939966636005579).abs() < 1e-12);
+
+            app.stack.clear();
+            for input in ["mode deg", "90", "sin", "1", "asin"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![1.0, 90.0]);
+
+            app.input = String::from("mode grad");
+            app.process_input().unwrap();
+            assert_eq!(
+                app.error.as_deref(),
+                Some("unknown angle mode: grad, expected deg or rad")
+            );
+
+            app.input = String::from("mode rad");
+            app.process_input().unwrap();
+            app.input = String::from("sin");
+            app.process_input().unwrap();
+            assert!((app.stack[1] - 0.8939966636005579).abs() < 1e-12);
+        }
+
+        #[test]
+        fn degrees_conversion() {
+            let mut app = App::new();
+            app.input = String::from("3.141592653589793"); // π
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.141592653589793]);
+
+            app.input = String::from("deg");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![180.0]);
+        }
+
+        #[test]
+        fn radians_conversion() {
+            let mut app = App::new();
+            app.input = String::from("180");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![180.0]);
+
+            app.input = String::from("rad");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.141592653589793]); // ~π
+        }
+
+        #[test]
+        fn factorial() {
+            let mut app = App::new();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0]);
+
+            app.input = String::from("!");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![120.0]);
+        }
+
+        #[test]
+        fn reciprocal() {
+            let mut app = App::new();
+            app.input = String::from("4");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![4.0]);
+
+            app.input = String::from("recip");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.25]);
+        }
+
+        #[test]
+        fn is_integer() {
+            let mut app = App::new();
+            app.input = String::from("5.0");
+            app.process_input().unwrap();
+            app.input = String::from("isint");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+
+            app.input = String::from("5.5");
+            app.process_input().unwrap();
+            app.input = String::from("isint");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 0.0]);
+        }
+
+        #[test]
+        fn log_base_10() {
+            let mut app = App::new();
+            app.input = String::from("100");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![100.0]);
+
+            app.input = String::from("log10");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.0]);
+        }
+
+        #[test]
+        fn log_base_natural() {
+            let mut app = App::new();
+            app.input = String::from("2.718281828459045"); // e
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.718281828459045]);
+
+            app.input = String::from("logn");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+        }
+
+        #[test]
+        fn log_base_2() {
+            let mut app = App::new();
+            app.input = String::from("8");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![8.0]);
+
+            app.input = String::from("log2");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.0]);
+        }
+
+        #[test]
+        fn celsius_to_fahrenheit() {
+            let mut app = App::new();
+            app.input = String::from("100");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![100.0]);
+
+            app.input = String::from("c2f");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![212.0]);
+        }
+
+        #[test]
+        fn fahrenheit_to_celsius() {
+            let mut app = App::new();
+            app.input = String::from("32");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![32.0]);
+
+            app.input = String::from("f2c");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0]);
+        }
+
+        #[test]
+        fn unit_conversion() {
+            let mut app = App::new();
+            app.input = String::from("1");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+
+            app.input = String::from("convert m ft");
+            app.process_input().unwrap();
+            assert!((app.stack[0] - 3.28084).abs() < 1e-5);
+        }
+
+        #[test]
+        fn unit_conversion_unknown_unit() {
+            let mut app = App::new();
+            app.input = String::from("1");
+            app.process_input().unwrap();
+
+            app.input = String::from("convert m parsec");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+            assert_eq!(app.error.as_deref(), Some("unknown unit: parsec"));
+        }
+
+        #[test]
+        fn range() {
+            let mut app = App::new();
+            app.input = String::from("2");
+            app.process_input().unwrap();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+
+            app.input = String::from("range");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.0, 3.0, 4.0, 5.0]);
+        }
+
+        #[test]
+        fn continued_fraction() {
+            let mut app = App::new();
+            app.input = String::from("phi");
+            app.process_input().unwrap();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+
+            app.input = String::from("cfrac");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0; 10]);
+
+            app.stack.clear();
+            app.input = String::from("4.25");
+            app.process_input().unwrap();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            app.input = String::from("cfrac");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![4.0, 4.0]);
+        }
+
+        #[test]
+        fn modular_power() {
+            let mut app = App::new();
+            for input in ["3", "4", "5", "modpow"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![1.0]);
+
+            for input in ["2", "3", "0", "modpow"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0, 0.0]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn modular_power_out_of_range() {
+            for stack in [
+                vec![3.0, 4.0, f64::NAN],
+                vec![3.0, f64::INFINITY, 5.0],
+                vec![5e19, 3.0, 1e20],
+            ] {
+                let mut app = App::new();
+                app.stack = stack.clone();
+                app.input = String::from("modpow");
+                app.process_input().unwrap();
+                assert_eq!(app.stack.len(), 3, "{stack:?}");
+                assert!(app.error.is_some(), "{stack:?}");
+            }
+
+            // The largest modulus allowed still squares without overflow
+            let mut app = App::new();
+            let modulus = 2f64.powi(53);
+            app.stack = vec![modulus - 1.0, 2.0, modulus];
+            app.input = String::from("modpow");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0]);
+        }
+
+        #[test]
+        fn gcd_and_lcm() {
+            let mut app = App::new();
+            for input in ["12", "18", "gcd", "4", "6", "lcm"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![6.0, 12.0]);
+
+            for input in ["-4", "gcd"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![6.0, 12.0, -4.0]);
+            assert_eq!(
+                app.error,
+                Some(String::from(
+                    "gcd needs non-negative integers no larger than 2^53, got -4"
+                ))
+            );
+
+            app.stack = vec![1e300, 1e300];
+            app.input = String::from("gcd");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1e300, 1e300]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn bitwise_operations() {
+            for (inputs, expected) in [
+                (["12", "10", "and"], 8.0),
+                (["12", "10", "or"], 14.0),
+                (["12", "10", "xor"], 6.0),
+                (["1", "4", "shl"], 16.0),
+                (["-16", "2", "shr"], -4.0),
+            ] {
+                let mut app = App::new();
+                for input in inputs {
+                    app.input = String::from(input);
+                    app.process_input().unwrap();
+                }
+                assert_eq!(app.stack, vec![expected], "{inputs:?}");
+            }
+
+            let mut app = App::new();
+            for input in ["2.5", "1", "and"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![2.5, 1.0]);
+            assert_eq!(
+                app.error,
+                Some(String::from("and needs integers in i64 range, got 2.5"))
+            );
+
+            for input in ["64", "shl"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![2.5, 1.0, 64.0]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn modular_inverse_out_of_range() {
+            for stack in [vec![3.0, f64::NEG_INFINITY], vec![3.0, 1e300]] {
+                let mut app = App::new();
+                app.stack = stack.clone();
+                app.input = String::from("modinv");
+                app.process_input().unwrap();
+                assert_eq!(app.stack, stack);
+                assert!(app
+                    .error
+                    .as_deref()
+                    .unwrap()
+                    .starts_with("modinv needs integers no larger than 2^53"));
+            }
+        }
+
+        #[test]
+        fn modular_inverse() {
+            let mut app = App::new();
+            for input in ["3", "11", "modinv"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![4.0]);
+
+            for input in ["6", "modinv"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![4.0, 6.0]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn baseline() {
+            let mut app = App::new();
+            app.input = String::from("checkbaseline");
+            app.process_input().unwrap();
+            assert!(app.error.is_some());
+
+            app.stack = vec![0.3];
+            app.input = String::from("baseline");
+            app.process_input().unwrap();
+
+            app.stack = vec![0.1 + 0.2];
+            app.input = String::from("checkbaseline");
+            app.process_input().unwrap();
+            assert_eq!(app.status.as_deref(), Some("baseline: pass"));
+
+            app.input = String::from("neg");
+            app.process_input().unwrap();
+            app.input = String::from("checkbaseline");
+            app.process_input().unwrap();
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn reduce() {
+            let mut app = App::new();
+            app.stack = vec![10.0, 3.0, 2.0];
+            app.input = String::from("reduce -");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0]);
+            assert!(app.fold_frames.is_empty());
+
+            app.stack = vec![1.0, 2.0, 3.0];
+            app.input = String::from("animate");
+            app.process_input().unwrap();
+            app.input = String::from("reduce *");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![6.0]);
+            assert_eq!(app.fold_frames.len(), 3);
+
+            app.input = String::from("reduce sqrt");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![6.0]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn reduce_animation_is_capped() {
+            let mut app = App::new();
+            app.animate_folds = true;
+            app.stack = (1..=1000).map(f64::from).collect();
+            app.input = String::from("reduce +");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![500_500.0]);
+            assert_eq!(app.fold_frames.len(), MAX_FOLD_FRAMES);
+        }
+
+        #[test]
+        fn dup_apply() {
+            let mut app = App::new();
+            app.stack = vec![16.0];
+            app.input = String::from("dupapply sqrt");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![16.0, 4.0]);
+
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![16.0]);
+
+            app.input = String::from("dupapply +");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![16.0]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn over2() {
+            let mut app = App::new();
+            app.stack = vec![2.0, 3.0];
+            app.input = String::from("over2 +");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.0, 3.0, 5.0]);
+
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![2.0, 3.0]);
+        }
+
+        #[test]
+        fn set_stack() {
+            let mut app = App::new();
+            app.stack = vec![9.0];
+            app.input = String::from("set 1 2 3");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
+
+            app.input = String::from("set 4 five 6");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
+            assert!(app.error.is_some());
+
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![9.0]);
+        }
+
+        #[test]
+        fn set_without_values() {
+            let mut app = App::new();
+            app.stack = vec![9.0];
+            app.input = String::from("set ");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![9.0]);
+            assert_eq!(app.error.as_deref(), Some("set is missing its arguments"));
+        }
+
+        #[test]
+        fn eval() {
+            let mut app = App::new();
+            app.stack = vec![1.0];
+            app.input = String::from("eval \"2 3 +\"");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 5.0]);
+
+            app.input = String::from("eval \"2 foo\"");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 5.0]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn eval_shares_settings() {
+            let mut app = App::new();
+            for input in [
+                "mode deg",
+                "eval \"90 sin\"",
+                "2 sto x",
+                "double = 2 *",
+                "eval \"3 double\"",
+                "eval \"rcl x\"",
+            ] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+                assert_eq!(app.error, None, "{input}");
+            }
+            assert_eq!(app.stack, vec![1.0, 6.0, 2.0]);
+        }
+
+        #[test]
+        fn named_formula() {
+            let mut app = App::new();
+            app.input = String::from("area = *");
+            app.process_input().unwrap();
+            assert_eq!(app.status.as_deref(), Some("area (2) = *"));
+
+            app.stack = vec![3.0, 4.0];
+            app.input = String::from("area");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![12.0]);
+
+            app.input = String::from("area");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![12.0]);
+            assert!(app.error.is_some());
+
+            app.input = String::from("swap = +");
+            app.process_input().unwrap();
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn count_matching() {
+            let mut app = App::new();
+            app.stack = vec![1.0, 5.0, 10.0, 4.0];
+            app.input = String::from("countgt");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 5.0, 10.0, 2.0]);
+
+            app.stack = vec![1.0, 5.0, 10.0, 5.0];
+            app.input = String::from("countlt");
+            app.process_input().unwrap();
+            assert_eq!(app.stack.last(), Some(&1.0));
+
+            app.stack = vec![0.1 + 0.2, 0.3, 1.0, 0.3];
+            app.input = String::from("counteq");
+            app.process_input().unwrap();
+            assert_eq!(app.stack.last(), Some(&2.0));
+        }
+
+        #[test]
+        fn filter_gt() {
+            let mut app = App::new();
+            app.stack = vec![1.0, 5.0, 10.0, 4.0];
+            app.input = String::from("filtergt");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0, 10.0]);
+
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 5.0, 10.0, 4.0]);
+        }
+
+        #[test]
+        fn argmax() {
+            let mut app = App::new();
+            app.stack = vec![3.0, 7.0, 2.0];
+            app.input = String::from("argmax");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.0, 7.0, 2.0, 1.0]);
+
+            app.stack = vec![7.0, 3.0, 7.0, 2.0];
+            app.input = String::from("argmax");
+            app.process_input().unwrap();
+            assert_eq!(app.stack.last(), Some(&1.0));
+        }
+
+        #[test]
+        fn lerp() {
+            let mut app = App::new();
+            app.stack = vec![0.0, 10.0, 0.5];
+            app.input = String::from("lerp");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0]);
+        }
+
+        #[test]
+        fn normalize_max() {
+            let mut app = App::new();
+            app.stack = vec![2.0, 4.0, 8.0];
+            app.input = String::from("normalizemax");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.25, 0.5, 1.0]);
+
+            app.stack = vec![0.0, 0.0];
+            app.input = String::from("normalizemax");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0, 0.0]);
+            assert!(app.error.is_some());
+
+            app.stack = vec![-2.0, -4.0];
+            app.input = String::from("normalizemax");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![-2.0, -4.0]);
+            assert_eq!(
+                app.error.as_deref(),
+                Some("normalizemax needs a positive largest value, got -2")
+            );
+
+            // Negative values are fine as long as the largest is positive
+            app.stack = vec![-2.0, 4.0];
+            app.input = String::from("normalizemax");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![-0.5, 1.0]);
+        }
+
+        #[test]
+        fn quit_shortcut() {
+            let mut app = App::new();
+            app.stack = vec![1.0];
+            assert!(!app.request_quit());
+
+            // With confirmation on, a non-empty stack asks first
+            app.confirm_destructive = true;
+            assert!(app.request_quit());
+            assert_eq!(app.pending.as_deref(), Some("quit"));
+            assert!(app.answer_pending(false));
+            assert_eq!(app.status.as_deref(), Some("quit cancelled"));
+
+            assert!(app.request_quit());
+            assert!(!app.answer_pending(true));
+
+            app.stack.clear();
+            assert!(!app.request_quit());
+        }
+
+        #[test]
+        fn confirm_clear() {
+            let mut app = App::new();
+            app.stack = vec![1.0, 2.0];
+            app.input = String::from("confirm");
+            app.process_input().unwrap();
+
+            app.input = String::from("clear");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 2.0]);
+            assert_eq!(app.pending.as_deref(), Some("clear"));
+
+            app.answer_pending(false);
+            assert_eq!(app.stack, vec![1.0, 2.0]);
+            assert_eq!(app.pending, None);
+
+            app.input = String::from("clear");
+            app.process_input().unwrap();
+            app.answer_pending(true);
+            assert!(app.stack.is_empty());
+            assert_eq!(app.pending, None);
+
+            // Non-destructive commands run straight away
+            app.input = String::from("5");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0]);
+            assert_eq!(app.pending, None);
+        }
+
+        #[test]
+        fn rounding() {
+            for (value, command, expected) in [
+                ("2.7", "floor", 2.0),
+                ("2.2", "ceil", 3.0),
+                ("2.5", "round", 3.0),
+                ("-2.7", "trunc", -2.0),
+            ] {
+                let mut app = App::new();
+                app.input = String::from(value);
+                app.process_input().unwrap();
+                app.input = String::from(command);
+                app.process_input().unwrap();
+                assert_eq!(app.stack, vec![expected], "{value} {command}");
+            }
+        }
+
+        #[test]
+        fn radix_literals() {
+            let mut app = App::new();
+            for input in ["0xff", "0o17", "0b1010", "-0x10"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![255.0, 15.0, 10.0, -16.0]);
+            assert_eq!(app.error, None);
+
+            for (input, error) in [
+                ("0xzz", "invalid hexadecimal literal: 0xzz"),
+                ("0b102", "invalid binary literal: 0b102"),
+                ("0x-1", "invalid hexadecimal literal: 0x-1"),
+                ("0o", "invalid octal literal: 0o"),
+            ] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+                assert_eq!(app.error.as_deref(), Some(error));
+            }
+            assert_eq!(app.stack, vec![255.0, 15.0, 10.0, -16.0]);
+        }
+
+        #[test]
+        fn to_int() {
+            let mut app = App::new();
+            for input in ["3.7", "toint"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![4.0]);
+            assert_eq!(app.status, None);
+
+            for input in ["-1e30", "toint"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![4.0, i64::MIN as f64]);
+            assert_eq!(
+                app.status.as_deref(),
+                Some("-1000000000000000000000000000000 is out of i64 range, saturated to -9223372036854775808")
+            );
+        }
+
+        #[test]
+        fn exponentials() {
+            let mut app = App::new();
+            for input in ["1", "exp"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert!((app.stack.pop().unwrap() - f64::consts::E).abs() < 1e-12);
+
+            for input in ["2", "exp10", "3", "alog"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![100.0, 1000.0]);
+
+            app.stack.clear();
+            for input in ["100", "log10", "exp10"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert!((app.stack.pop().unwrap() - 100.0).abs() < 1e-9);
+
+            for input in ["5", "logn", "exp"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert!((app.stack.pop().unwrap() - 5.0).abs() < 1e-12);
+        }
+
+        #[test]
+        fn atan2() {
+            let mut app = App::new();
+            for input in ["1", "1", "atan2"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert!((app.stack.pop().unwrap() - f64::consts::FRAC_PI_4).abs() < 1e-12);
+
+            for input in ["1", "0", "atan2"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert!((app.stack.pop().unwrap() - f64::consts::FRAC_PI_2).abs() < 1e-12);
+
+            // y sits below x, so `0 1 atan2` is the angle of the point (1, 0)
+            for input in ["0", "1", "atan2"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack.pop().unwrap(), 0.0);
+        }
+
+        #[test]
+        fn harmonic_mean() {
+            let mut app = App::new();
+            app.stack = vec![1.0, 2.0, 4.0];
+            app.input = String::from("harmean");
+            app.process_input().unwrap();
+            assert_eq!(app.stack.len(), 1);
+            assert!((app.stack[0] - 12.0 / 7.0).abs() < 1e-12);
+
+            app.stack = vec![1.0, 0.0];
+            app.input = String::from("harmean");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 0.0]);
+            assert!(app.error.is_some());
+        }
+
+        #[test]
+        fn sum() {
+            let mut app = App::new();
+            app.input = String::from("1 2 3 4 sum");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert!(app.stack.is_empty());
+
+            app.input = String::from("sum");
+            app.process_input().unwrap();
+            assert!(app.stack.is_empty());
+            assert_eq!(app.error.as_deref(), Some("need 1 value, have 0"));
+        }
+
+        #[test]
+        fn aggregates() {
+            for (values, command, expected) in [
+                ("2 3 4", "prod", 24.0),
+                ("1 2 3", "mean", 2.0),
+                ("2 4 4 4 5 5 7 9", "stddev", 2.0),
+                ("5", "stddev", 0.0),
+            ] {
+                let mut app = App::new();
+                app.input = format!("{values} {command}");
+                app.process_input().unwrap();
+                assert_eq!(app.stack, vec![expected], "{values} {command}");
+            }
+
+            let mut app = App::new();
+            for command in ["prod", "mean", "stddev"] {
+                app.input = String::from(command);
+                app.process_input().unwrap();
+                assert_eq!(app.error.as_deref(), Some("need 1 value, have 0"));
+            }
+        }
+
+        #[test]
+        fn decimal_places() {
+            let mut app = App::new();
+            app.input = String::from("3.14");
+            app.process_input().unwrap();
+
+            app.input = String::from("decimals");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.14, 2.0]);
+        }
+
+        #[test]
+        fn decimal_places_in_formula() {
+            let mut app = App::new();
+            for input in ["f = decimals +", "2.5", "f"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.error, None);
+            assert_eq!(app.stack, vec![3.5]);
+        }
+
+        #[test]
+        fn vector_addition() {
+            let mut app = App::new();
+            for value in ["1", "2", "3", "4", "5", "6", "3"] {
+                app.input = String::from(value);
+                app.process_input().unwrap();
+            }
+
+            app.input = String::from("vadd");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0, 7.0, 9.0]);
+        }
+
+        #[test]
+        fn vector_multiplication() {
+            let mut app = App::new();
+            for value in ["10", "1", "2", "3", "4", "2"] {
+                app.input = String::from(value);
+                app.process_input().unwrap();
+            }
+
+            app.input = String::from("vmul");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0, 3.0, 8.0]);
+        }
+
+        #[test]
+        fn cross_product() {
+            let mut app = App::new();
+            for value in ["1", "0", "0", "0", "1", "0"] {
+                app.input = String::from(value);
+                app.process_input().unwrap();
+            }
+
+            app.input = String::from("cross");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![0.0, 0.0, 1.0]);
+        }
+
+        #[test]
+        fn norm() {
+            let mut app = App::new();
+            for value in ["3", "4", "2"] {
+                app.input = String::from(value);
+                app.process_input().unwrap();
+            }
+
+            app.input = String::from("norm");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0]);
+        }
+
+        #[test]
+        fn unknown_command_suggestion() {
+            let mut app = App::new();
+            app.input = String::from("16");
+            app.process_input().unwrap();
+
+            app.input = String::from("suqrt");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![16.0]);
+            assert_eq!(
+                app.error.as_deref(),
+                Some("unknown command: suqrt, did you mean: sqrt?")
+            );
+
+            app.input = String::from("xyzzyx");
+            app.process_input().unwrap();
+            assert_eq!(app.error.as_deref(), Some("unknown command: xyzzyx"));
+        }
+
+        #[test]
+        fn assert_passes() {
+            let mut app = App::new();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+
+            app.input = String::from("assert");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0]);
+            assert_eq!(app.error, None);
+        }
+
+        #[test]
+        fn assert_fails() {
+            let mut app = App::new();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+            app.input = String::from("6");
+            app.process_input().unwrap();
+
+            app.input = String::from("assert");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0, 6.0]);
+            assert_eq!(
+                app.error.as_deref(),
+                Some("assertion failed: expected 6, got 5")
+            );
+        }
+
+        #[test]
+        fn print() {
+            let mut app = App::new();
+            app.input = String::from("1");
+            app.process_input().unwrap();
+            app.input = String::from("2.5");
+            app.process_input().unwrap();
+
+            app.input = String::from("print");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 2.5]);
+            assert_eq!(app.status.as_deref(), Some("2.5"));
+        }
+
+        #[test]
+        fn swap() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+
+            app.input = String::from("swap");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![5.0, 10.0]);
+        }
+
+        #[test]
+        fn swap_pairs() {
+            let mut app = App::new();
+            for value in ["1", "2", "3", "4"] {
+                app.input = String::from(value);
+                app.process_input().unwrap();
+            }
+
+            app.input = String::from("swap2");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.0, 4.0, 1.0, 2.0]);
+        }
+
+        #[test]
+        fn roll_n() {
+            let mut app = App::new();
+            for value in ["1", "2", "3", "4", "3"] {
+                app.input = String::from(value);
+                app.process_input().unwrap();
+            }
+
+            app.input = String::from("rolln");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 3.0, 4.0, 2.0]);
+        }
+
+        #[test]
+        fn clear() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+
+            app.input = String::from("clear");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![]);
+        }
+
+        #[test]
+        fn drop() {
+            let mut app = App::new();
+            app.input = String::from("10");
+            app.process_input().unwrap();
+            app.input = String::from("5");
+            app.process_input().unwrap();
+
+            app.input = String::from("drop");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+        }
+
+        #[test]
+        fn keep_top() {
+            let mut app = App::new();
+            for value in ["1", "2", "3"] {
+                app.input = String::from(value);
+                app.process_input().unwrap();
+            }
+
+            app.input = String::from("keeptop");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.0]);
+
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
+        }
+
+        #[test]
+        fn undo_redo() {
+            let mut app = App::new();
+
+            // Push 3.0 to the stack
+            app.input = String::from("3");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.0]);
+
+            // Push 7.0 to the stack
+            app.input = String::from("7");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.0, 7.0]);
+
+            // Perform addition
+            app.input = String::from("+");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+
+            // Undo the addition
+            app.input = String::from("undo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![3.0, 7.0]);
+
+            // Redo the addition
+            app.input = String::from("redo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![10.0]);
+        }
+
+        #[test]
+        fn push_infinity() {
+            let mut app = App::new();
+            app.input = String::from("inf");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![f64::INFINITY])
+        }
+
+        #[test]
+        fn push_pi() {
+            let mut app = App::new();
+            app.input = String::from("pi");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![f64::consts::PI])
+        }
+
+        #[test]
+        fn push_e_and_tau() {
+            let mut app = App::new();
+            for constant in ["e", "tau", "undo"] {
+                app.input = String::from(constant);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![f64::consts::E]);
+            app.input = String::from("redo");
+            app.process_input().unwrap();
+            assert_eq!(app.stack, vec![f64::consts::E, f64::consts::TAU]);
+
+            app.input = String::from("pi");
+            app.process_input().unwrap();
+            assert_eq!(app.stack.last(), Some(&3.141592653589793));
+        }
+
+        #[test]
+        fn push_named_constants() {
+            let mut app = App::new();
+            for constant in ["phi", "sqrt2", "sqrt3", "ln2"] {
+                app.input = String::from(constant);
+                app.process_input().unwrap();
+            }
+            assert_eq!(
+                app.stack,
+                vec![
+                    1.618033988749895,
+                    f64::consts::SQRT_2,
+                    3f64.sqrt(),
+                    f64::consts::LN_2
+                ]
+            );
+        }
+
+        #[test]
+        fn quit() {
+            let mut app = App::new();
+            app.input = String::from("quit");
+            assert!(!app.process_input().unwrap());
+        }
+    }
+
+    mod function_tests {
+        use core::f64;
+
+        use super::{App, StackDelta};
+
+        /// The states `undo` would step back through, oldest first.
+        fn undo_states(app: &App) -> Vec<Vec<f64>> {
+            let mut stack = app.stack.clone();
+            let mut states = Vec::new();
+            if let Some((keep, removed)) = &app.undo.unsaved {
+                stack.truncate(*keep);
+                stack.extend_from_slice(removed);
+                states.push(stack.clone());
+            }
+            let mut node = app.undo.head;
+            while let Some(delta) = node.and_then(|node| app.undo.nodes[node].delta.as_ref()) {
+                delta.undo(&mut stack);
+                states.push(stack.clone());
+                node = node.and_then(|node| app.undo.nodes[node].parent);
+            }
+            states.reverse();
+            states
+        }
+
+        #[test]
+        fn push_number() {
+            let mut app = App::new();
+            app.push_number(5.6);
+            assert_eq!(app.stack.pop().unwrap(), 5.6);
+        }
+
+        #[test]
+        fn addition() {
+            let mut app = App::new();
+            app.push_number(5.0);
+            app.push_number(3.0);
+            app.perform_operation(|a, b| a + b);
+            assert_eq!(app.stack.pop().unwrap(), 8.0);
+        }
+
+        #[test]
+        fn subtraction() {
+            let mut app = App::new();
+            app.push_number(10.0);
+            app.push_number(4.0);
+            app.perform_operation(|a, b| a - b);
+            assert_eq!(app.stack.pop().unwrap(), 6.0);
+        }
+
+        #[test]
+        fn multiplication() {
+            let mut app = App::new();
+            app.push_number(2.0);
+            app.push_number(3.0);
+            app.perform_operation(|a, b| a * b);
+            assert_eq!(app.stack.pop().unwrap(), 6.0);
+        }
+
+        #[test]
+        fn division() {
+            let mut app = App::new();
+            app.push_number(10.0);
+            app.push_number(2.0);
+            app.perform_operation(|a, b| a / b);
+            assert_eq!(app.stack.pop().unwrap(), 5.0);
+        }
+
+        #[test]
+        fn clone() {
+            let mut app = App::new();
+            app.push_number(10.0);
+            app.push_number(2.0);
+            app.perform_clone();
+            assert_eq!(app.stack.pop().unwrap(), 2.0);
+        }
+
+        #[test]
+        fn modulo() {
+            let mut app = App::new();
+            app.push_number(17.0);
+            app.push_number(5.0);
+            app.perform_operation(|a, b| a % b);
+            assert_eq!(app.stack.pop().unwrap(), 2.0);
+        }
+
+        #[test]
+        fn exponent() {
+            let mut app = App::new();
+            app.push_number(4.0);
+            app.push_number(5.0);
+            app.perform_operation(|a, b| b.powf(a));
+            assert_eq!(app.stack.pop().unwrap(), 625.0);
+        }
+
+        #[test]
+        fn neg() {
+            let mut app = App::new();
+            app.push_number(4.0);
+            app.perform_single_operand_operation(|a| -a);
+            assert_eq!(app.stack.pop().unwrap(), -4.0);
+        }
+
+        #[test]
+        fn abs() {
+            let mut app = App::new();
+            app.push_number(-4.0);
+            app.perform_single_operand_operation(|a| a.abs());
+            assert_eq!(app.stack.pop().unwrap(), 4.0);
+        }
+
+        #[test]
+        fn sqrt() {
+            let mut app = App::new();
+            app.push_number(9.0);
+            app.perform_single_operand_operation(|a| a.sqrt());
+            assert_eq!(app.stack.pop().unwrap(), 3.0);
+        }
+
+        #[test]
+        fn sin() {
             let mut app = App::new();
-            app.input = String::from("10");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0]);
+            app.push_number(9.0);
+            app.perform_single_operand_operation(|a| a.sin());
+            assert_eq!(app.stack.pop().unwrap(), 0.4121184852417566);
+        }
 
-            app.input = String::from("95.678");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0, 95.678]);
+        #[test]
+        fn cos() {
+            let mut app = App::new();
+            app.push_number(5.0);
+            app.perform_single_operand_operation(|a| a.cos());
+            assert_eq!(app.stack.pop().unwrap(), 0.28366218546322625);
+        }
 
-            app.input = String::from("+");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![105.678]);
+        #[test]
+        fn tan() {
+            let mut app = App::new();
+            app.push_number(6.0);
+            app.perform_single_operand_operation(|a| a.tan());
+            assert_eq!(app.stack.pop().unwrap(), -0.29100619138474915);
         }
 
         #[test]
-        fn subtraction() {
+        fn asin() {
             let mut app = App::new();
-            app.input = String::from("10");
-            app.process_input().unwrap();
+            app.push_number(0.6);
+            app.perform_single_operand_operation(|a| a.asin());
+            assert_eq!(app.stack.pop().unwrap(), 0.6435011087932844);
+        }
+
+        #[test]
+        fn acos() {
+            let mut app = App::new();
+            app.push_number(0.7);
+            app.perform_single_operand_operation(|a| a.acos());
+            assert_eq!(app.stack.pop().unwrap(), 0.7953988301841436);
+        }
+
+        #[test]
+        fn atan() {
+            let mut app = App::new();
+            app.push_number(5.0);
+            app.perform_single_operand_operation(|a| a.atan());
+            assert_eq!(app.stack.pop().unwrap(), 1.373400766945016);
+        }
+
+        #[test]
+        fn convert_to_degrees() {
+            let mut app = App::new();
+            app.push_number(1.0);
+            app.perform_single_operand_operation(|a| a.to_degrees());
+            assert_eq!(app.stack.pop().unwrap(), 57.29577951308232);
+        }
+
+        #[test]
+        fn convert_to_radians() {
+            let mut app = App::new();
+            app.push_number(95.0);
+            app.perform_single_operand_operation(|a| a.to_radians());
+            assert_eq!(app.stack.pop().unwrap(), 1.6580627893946132);
+        }
+
+        #[test]
+        fn factorial() {
+            let mut app = App::new();
+            app.push_number(5.0);
+            app.perform_factorial();
+            assert_eq!(app.stack.pop().unwrap(), 120.0);
+        }
+
+        #[test]
+        fn recipricol() {
+            let mut app = App::new();
+            app.push_number(4.0);
+            app.perform_single_operand_operation(|a| 1.0 / a);
+            assert_eq!(app.stack.pop().unwrap(), 0.25);
+        }
+
+        #[test]
+        fn log10() {
+            let mut app = App::new();
+            app.push_number(50.0);
+            app.perform_single_operand_operation(|a| a.log(10.0));
+            assert_eq!(app.stack.pop().unwrap(), 1.6989700043360185);
+        }
+
+        #[test]
+        fn logn() {
+            let mut app = App::new();
+            app.push_number(50.0);
+            app.perform_single_operand_operation(|a| a.ln());
+            assert_eq!(app.stack.pop().unwrap(), 3.912023005428146);
+        }
+
+        #[test]
+        fn log2() {
+            let mut app = App::new();
+            app.push_number(50.0);
+            app.perform_single_operand_operation(|a| a.log(2.0));
+            assert_eq!(app.stack.pop().unwrap(), 5.643856189774724);
+        }
+
+        #[test]
+        fn push_infinity() {
+            let mut app = App::new();
+            app.push_infinity();
+            assert_eq!(app.stack.pop().unwrap(), f64::INFINITY)
+        }
+
+        #[test]
+        fn push_pi() {
+            let mut app = App::new();
+            app.push_pi();
+            assert_eq!(app.stack.pop().unwrap(), f64::consts::PI)
+        }
+
+        #[test]
+        fn undo_redo() {
+            let mut app = App::new();
+            app.push_number(3.0);
+            app.push_number(7.0);
+            app.perform_operation(|a, b| a + b);
+
+            // Verify that the stack has the result of the addition
             assert_eq!(app.stack, vec![10.0]);
 
-            app.input = String::from("4");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0, 4.0]);
+            // Undo the addition, should revert to the original stack state
+            app.undo();
+            assert_eq!(app.stack, vec![3.0, 7.0]);
 
-            app.input = String::from("-");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![6.0]);
+            // Redo the addition, should return the stack to [10.0]
+            app.redo();
+            assert_eq!(app.stack, vec![10.0]);
         }
 
         #[test]
-        fn multiplication() {
+        fn undo_tree_keeps_branches() {
             let mut app = App::new();
-            app.input = String::from("2");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![2.0]);
+            app.push_number(1.0);
+            app.push_number(2.0);
+            app.undo();
+            // A change after undoing starts a second branch from [1]
+            app.push_number(3.0);
+            assert_eq!(app.stack, vec![1.0, 3.0]);
+            assert_eq!(undo_states(&app), vec![vec![], vec![1.0]]);
+            app.redo();
+            assert_eq!(app.error.as_deref(), Some("nothing to redo"));
 
-            app.input = String::from("3");
+            // Redo follows the newest branch by default
+            app.error = None;
+            app.undo();
+            assert_eq!(app.stack, vec![1.0]);
+            app.redo();
+            assert_eq!(app.stack, vec![1.0, 3.0]);
+
+            // The older branch is still there
+            app.undo();
+            app.input = String::from("branch");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![2.0, 3.0]);
+            assert_eq!(app.status.as_deref(), Some("redo follows branch 1 of 2"));
+            app.redo();
+            assert_eq!(app.stack, vec![1.0, 2.0]);
+            assert_eq!(app.undo.redo_depth(), 0);
 
-            app.input = String::from("*");
+            app.undo();
+            app.undo();
+            assert!(app.stack.is_empty());
+            assert_eq!(app.undo.redo_depth(), 2);
+            app.input = String::from("branch");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![6.0]);
+            assert_eq!(app.error.as_deref(), Some("no other branches here"));
         }
 
         #[test]
-        fn division() {
+        fn undo_stores_deltas() {
             let mut app = App::new();
-            app.input = String::from("10");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0]);
+            app.stack = (0..1000).map(f64::from).collect();
+            let before = app.stack.clone();
+            app.perform_operation(|a, b| a + b);
+            app.push_number(5.0);
 
-            app.input = String::from("2");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0, 2.0]);
+            // Only the values the addition touched are kept, not the whole stack
+            let head = app.undo.head.unwrap();
+            assert_eq!(
+                app.undo.nodes[head].delta,
+                Some(StackDelta {
+                    keep: 998,
+                    removed: vec![998.0, 999.0],
+                    added: vec![1997.0],
+                })
+            );
 
-            app.input = String::from("/");
+            app.undo();
+            app.undo();
+            assert_eq!(app.stack, before);
+            app.redo();
+            app.redo();
+            assert_eq!(app.stack.len(), 1000);
+            assert_eq!(app.stack[998..], [1997.0, 5.0]);
+        }
+
+        #[test]
+        fn whole_stack_snapshots_are_trimmed() {
+            let mut app = App::new();
+            app.stack = (0..1000).map(f64::from).collect();
+            // A line of several commands can't say how deep it will reach, so it saves everything
+            app.input = String::from("3 4 +");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![5.0]);
+            app.push_number(1.0);
+
+            let head = app.undo.head.unwrap();
+            assert_eq!(
+                app.undo.nodes[head].delta,
+                Some(StackDelta {
+                    keep: 1000,
+                    removed: vec![],
+                    added: vec![7.0],
+                })
+            );
+            app.undo();
+            app.undo();
+            assert_eq!(app.stack.len(), 1000);
         }
 
         #[test]
-        fn modulus() {
+        fn deltas_reverse_every_kind_of_change() {
+            for command in [
+                "5",
+                "neg",
+                "+",
+                "compound",
+                "modinv",
+                "clone",
+                "dupbottom",
+                "swap",
+                "rot",
+                "over",
+                "swap2",
+                "1 rolln",
+                "1 swapat",
+                "2 pick",
+                "2 roll",
+                "drop",
+                "clear",
+                "keeptop",
+                "3 filtergt",
+                "3 countgt",
+                "normalizemax",
+                "harmean",
+                "reduce +",
+                "set 4 5",
+                "sto x",
+                "pin",
+                "!",
+                "2 vadd",
+                "cross",
+                "2 norm",
+                "range",
+                "runlength",
+                "8 cfrac",
+                "dupapply sqrt",
+                "3 4 + 5 *",
+            ] {
+                let mut app = App::new();
+                app.stack = vec![2.0, 7.0, 3.0, 6.0, 4.0, 5.0, 3.0];
+                let before = app.stack.clone();
+                app.input = String::from(command);
+                app.process_input().unwrap();
+                assert_eq!(app.error, None, "{command}");
+                let after = app.stack.clone();
+
+                app.undo();
+                assert_eq!(app.stack, before, "undoing {command}");
+                app.redo();
+                assert_eq!(app.stack, after, "redoing {command}");
+                app.undo();
+                assert_eq!(app.stack, before, "undoing {command} again");
+            }
+        }
+
+        #[test]
+        fn deltas_combine() {
+            // [1, 2, 3] -> [1, 2, 4, 5]
+            let first = StackDelta {
+                keep: 2,
+                removed: vec![3.0],
+                added: vec![4.0, 5.0],
+            };
+            // then -> [1, 9], reaching below the first change
+            let deeper = StackDelta {
+                keep: 1,
+                removed: vec![2.0, 4.0, 5.0],
+                added: vec![9.0],
+            };
+            // or -> [1, 2, 4, 6, 7], staying above it
+            let shallower = StackDelta {
+                keep: 3,
+                removed: vec![5.0],
+                added: vec![6.0, 7.0],
+            };
+            for (second, expected) in [
+                (deeper, vec![1.0, 9.0]),
+                (shallower, vec![1.0, 2.0, 4.0, 6.0, 7.0]),
+            ] {
+                let combined = first.then(&second);
+                let mut stack = vec![1.0, 2.0, 3.0];
+                combined.redo(&mut stack);
+                assert_eq!(stack, expected);
+                combined.undo(&mut stack);
+                assert_eq!(stack, vec![1.0, 2.0, 3.0]);
+            }
+        }
+
+        #[test]
+        fn undo_is_capped() {
             let mut app = App::new();
-            app.input = String::from("10");
+            for i in 0..150 {
+                app.push_number(f64::from(i));
+                assert!(app.undo.nodes.len() <= app.max_undo);
+            }
+            assert_eq!(undo_states(&app).len(), 100);
+            app.undo();
+            assert_eq!(app.stack.len(), 149);
+
+            // The oldest states go first, wherever they are in the tree
+            let mut app = App::new();
+            app.max_undo = 3;
+            for value in [1.0, 2.0, 3.0] {
+                app.push_number(value);
+            }
+            app.undo();
+            app.push_number(4.0);
+            app.push_number(5.0);
+            assert_eq!(app.undo.nodes.len(), 3);
+            assert_eq!(undo_states(&app), vec![vec![1.0, 2.0], vec![1.0, 2.0, 4.0]]);
+            app.undo();
+            app.undo();
+            app.redo();
+            assert_eq!(app.stack, vec![1.0, 2.0, 4.0]);
+            app.undo();
+            app.input = String::from("branch");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0]);
+            app.redo();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
+        }
 
-            app.input = String::from("3");
+        #[test]
+        fn nothing_to_undo() {
+            let mut app = App::new();
+            app.undo();
+            assert_eq!(app.error.as_deref(), Some("nothing to undo"));
+
+            app.error = None;
+            app.redo();
+            assert_eq!(app.error.as_deref(), Some("nothing to redo"));
+        }
+
+        #[test]
+        fn operation_underflow() {
+            let mut app = App::new();
+            app.push_number(1.0);
+            app.perform_operation(|a, b| a + b);
+            assert_eq!(app.stack, vec![1.0]);
+            assert_eq!(app.error.as_deref(), Some("need 2 values, have 1"));
+        }
+
+        #[test]
+        fn undo_recording_toggle() {
+            let mut app = App::new();
+            app.input = String::from("noundo");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0, 3.0]);
+            app.push_number(1.0);
+            app.push_number(2.0);
+            app.perform_operation(|a, b| a + b);
+            assert!(undo_states(&app).is_empty());
 
-            app.input = String::from("%");
+            app.input = String::from("undoon");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![1.0]);
+            app.push_number(4.0);
+            assert_eq!(undo_states(&app), vec![vec![3.0]]);
+        }
+
+        #[test]
+        fn undo_after_unrecorded_changes() {
+            let mut app = App::new();
+            for input in [
+                "1", "2", "3", "noundo", "clear", "4 5 6 7", "undoon", "undo",
+            ] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![4.0, 5.0, 6.0, 7.0]);
+            assert_eq!(app.error.as_deref(), Some("nothing to undo"));
+
+            // Pausing without touching the stack keeps the history
+            for input in ["8", "noundo", "undoon", "undo"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.stack, vec![4.0, 5.0, 6.0, 7.0]);
+            assert_eq!(app.error, None);
+        }
+
+        #[test]
+        fn clear() {
+            let mut app = App::new();
+            app.push_number(42.0);
+            app.perform_clear();
+            assert!(app.stack.is_empty());
+        }
+
+        #[test]
+        fn drop() {
+            let mut app = App::new();
+            app.push_number(5.0);
+            app.push_number(10.0);
+            app.perform_drop();
+            assert_eq!(app.stack.len(), 1);
+            assert_eq!(app.stack.pop().unwrap(), 5.0);
         }
 
         #[test]
-        fn exponentiation() {
+        fn underflow_errors() {
             let mut app = App::new();
-            app.input = String::from("2");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![2.0]);
+            app.perform_single_operand_operation(|a| -a);
+            assert_eq!(app.error.as_deref(), Some("need 1 value, have 0"));
 
-            app.input = String::from("3");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![2.0, 3.0]);
+            app.push_number(1.0);
+            app.perform_swap();
+            assert_eq!(app.error.as_deref(), Some("need 2 values, have 1"));
 
-            app.input = String::from("^");
+            app.error = None;
+            app.input = String::from("+");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![9.0]);
+            assert_eq!(app.error.as_deref(), Some("need 2 values, have 1"));
+
+            app.perform_drop();
+            app.error = None;
+            app.perform_drop();
+            assert_eq!(app.error.as_deref(), Some("need 1 value, have 0"));
+            assert!(app.stack.is_empty());
         }
 
         #[test]
-        fn negation() {
+        fn dedup() {
             let mut app = App::new();
-            app.input = String::from("10");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0]);
+            app.stack = vec![1.0, 1.0, 2.0, 2.0, 3.0];
+            app.perform_dedup();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
 
-            app.input = String::from("neg");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![-10.0]);
+            // Only neighbours count, and rounding noise doesn't stop a match
+            app.stack = vec![1.0, 0.1 + 0.2, 0.3, 1.0];
+            app.perform_dedup();
+            assert_eq!(app.stack, vec![1.0, 0.1 + 0.2, 1.0]);
+
+            app.undo();
+            assert_eq!(app.stack, vec![1.0, 0.1 + 0.2, 0.3, 1.0]);
         }
 
         #[test]
-        fn absolute_value() {
+        fn copy_precision_keeps_stack_on_failure() {
             let mut app = App::new();
-            app.input = String::from("-5");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![-5.0]);
+            app.stack = vec![1.5, 2.0];
+            app.copy_with_precision();
+            // Whether a clipboard is available depends on where the tests run
+            if app.error.is_some() {
+                assert_eq!(app.stack, vec![1.5, 2.0]);
+            } else {
+                assert_eq!(app.stack, vec![1.5]);
+            }
+        }
 
-            app.input = String::from("abs");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![5.0]);
+        #[test]
+        fn copy_precision_out_of_range() {
+            for precision in [1e6, 21.0, -1.0, 2.5, f64::NAN] {
+                let mut app = App::new();
+                app.stack = vec![1.5, precision];
+                app.copy_with_precision();
+                assert_eq!(app.stack.len(), 2, "{precision}");
+                assert_eq!(
+                    app.error,
+                    Some(String::from(
+                        "precision must be a whole number from 0 to 20"
+                    )),
+                    "{precision}"
+                );
+            }
         }
 
         #[test]
-        fn square_root() {
+        fn run_length() {
             let mut app = App::new();
-            app.input = String::from("16");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![16.0]);
+            app.stack = vec![1.0, 1.0, 2.0, 3.0, 3.0, 3.0, 1.0];
+            app.perform_run_length();
+            assert_eq!(app.stack, vec![2.0, 1.0, 3.0, 1.0]);
 
-            app.input = String::from("sqrt");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![4.0]);
+            app.stack = vec![0.1 + 0.2, 0.3];
+            app.perform_run_length();
+            assert_eq!(app.stack, vec![2.0]);
+
+            app.undo();
+            assert_eq!(app.stack, vec![0.1 + 0.2, 0.3]);
         }
 
         #[test]
-        fn sine() {
+        fn pin_and_unpin() {
             let mut app = App::new();
-            app.input = String::from("0");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![0.0]);
+            app.stack = vec![1.0, 2.5];
+            app.perform_pin();
+            app.push_number(4.0);
+            app.perform_pin();
+            assert_eq!(app.stack, vec![1.0]);
+            assert_eq!(app.pinned_text(), "pinned: 2.5, 4");
 
-            app.input = String::from("sin");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![0.0]);
+            // Pinned values aren't part of the stack, so clearing it leaves them
+            app.perform_clear();
+            assert!(app.stack.is_empty());
+            assert_eq!(app.pinned, vec![2.5, 4.0]);
+
+            app.perform_unpin();
+            assert_eq!(app.pinned_text(), "pinned: 2.5");
+            app.perform_unpin();
+            assert_eq!(app.pinned_text(), "");
+            app.perform_unpin();
+            assert_eq!(app.error.as_deref(), Some("nothing is pinned"));
         }
 
         #[test]
-        fn cosine() {
+        fn pin_undo_and_redo() {
             let mut app = App::new();
-            app.input = String::from("0");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![0.0]);
+            for input in ["1", "2", "pin"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            app.undo();
+            assert_eq!(app.stack, vec![1.0, 2.0]);
+            assert!(app.pinned.is_empty());
+            app.redo();
+            assert_eq!(app.stack, vec![1.0]);
+            assert_eq!(app.pinned, vec![2.0]);
 
-            app.input = String::from("cos");
+            app.perform_unpin();
+            app.undo();
+            assert_eq!(app.pinned, vec![2.0]);
+
+            // A line of several commands is undone as one step, pins included
+            app.input = String::from("3 pin 4 pin");
             app.process_input().unwrap();
+            assert_eq!(app.pinned, vec![2.0, 3.0, 4.0]);
+            app.undo();
             assert_eq!(app.stack, vec![1.0]);
+            assert_eq!(app.pinned, vec![2.0]);
         }
 
         #[test]
-        fn tangent() {
+        fn dup_bottom() {
             let mut app = App::new();
-            app.input = String::from("0");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![0.0]);
+            app.perform_dup_bottom();
+            assert!(app.error.is_some());
 
-            app.input = String::from("tan");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![0.0]);
+            app.stack = vec![1.0, 2.0, 3.0];
+            app.perform_dup_bottom();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0, 1.0]);
+            assert_eq!(undo_states(&app), vec![vec![1.0, 2.0, 3.0]]);
         }
 
         #[test]
-        fn arcsine() {
+        fn swap_at() {
             let mut app = App::new();
-            app.input = String::from("1");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![1.0]);
+            app.stack = vec![10.0, 20.0, 30.0, 1.0];
+            app.perform_swap_at();
+            assert_eq!(app.stack, vec![20.0, 10.0, 30.0]);
 
-            app.input = String::from("asin");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![1.5707963267948966]); // ~π/2
+            app.push_number(2.0);
+            app.perform_swap_at();
+            assert_eq!(app.stack, vec![20.0, 10.0, 30.0, 2.0]);
+            assert_eq!(app.error.as_deref(), Some("need 5 values, have 4"));
         }
 
         #[test]
-        fn arccosine() {
+        fn rot() {
             let mut app = App::new();
-            app.input = String::from("1");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![1.0]);
+            app.stack = vec![1.0, 2.0, 3.0, 4.0];
+            app.perform_rot();
+            assert_eq!(app.stack, vec![1.0, 3.0, 4.0, 2.0]);
+            assert_eq!(undo_states(&app), vec![vec![1.0, 2.0, 3.0, 4.0]]);
+        }
 
-            app.input = String::from("acos");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![0.0]);
+        #[test]
+        fn rot_needs_three_values() {
+            let mut app = App::new();
+            app.stack = vec![1.0, 2.0];
+            app.perform_rot();
+            assert_eq!(app.stack, vec![1.0, 2.0]);
+            assert_eq!(app.error.as_deref(), Some("need 3 values, have 2"));
+            assert!(undo_states(&app).is_empty());
         }
 
         #[test]
-        fn arctangent() {
+        fn over() {
             let mut app = App::new();
-            app.input = String::from("1");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![1.0]);
+            app.stack = vec![3.0, 7.0];
+            app.push_number(1.0);
+            app.undo();
+            app.perform_over();
+            assert_eq!(app.stack, vec![3.0, 7.0, 3.0]);
+            assert_eq!(undo_states(&app), vec![vec![3.0, 7.0]]);
+            assert_eq!(app.undo.redo_depth(), 0);
+        }
 
-            app.input = String::from("atan");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![0.7853981633974483]); // ~π/4
+        #[test]
+        fn over_needs_two_values() {
+            let mut app = App::new();
+            app.stack = vec![3.0];
+            app.perform_over();
+            assert_eq!(app.stack, vec![3.0]);
+            assert_eq!(app.error.as_deref(), Some("need 2 values, have 1"));
         }
 
         #[test]
-        fn degrees_conversion() {
+        fn pick() {
             let mut app = App::new();
-            app.input = String::from("3.141592653589793"); // π
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![3.141592653589793]);
+            app.stack = vec![10.0, 20.0, 30.0, 2.0];
+            app.perform_pick();
+            assert_eq!(app.stack, vec![10.0, 20.0, 30.0, 10.0]);
+        }
 
-            app.input = String::from("deg");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![180.0]);
+        #[test]
+        fn roll() {
+            let mut app = App::new();
+            app.stack = vec![10.0, 20.0, 30.0, 2.0];
+            app.perform_roll();
+            assert_eq!(app.stack, vec![20.0, 30.0, 10.0]);
         }
 
         #[test]
-        fn radians_conversion() {
+        fn pick_and_roll_out_of_range() {
             let mut app = App::new();
-            app.input = String::from("180");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![180.0]);
+            app.stack = vec![10.0, 20.0, 3.0];
+            app.perform_pick();
+            assert_eq!(app.error.as_deref(), Some("need 5 values, have 3"));
+
+            app.error = None;
+            app.perform_roll();
+            assert_eq!(app.error.as_deref(), Some("need 5 values, have 3"));
+
+            app.stack = vec![10.0, 20.0, 1.5];
+            app.perform_pick();
+            assert!(app
+                .error
+                .as_deref()
+                .unwrap()
+                .contains("non-negative integer"));
+            assert_eq!(app.stack, vec![10.0, 20.0, 1.5]);
+            assert!(undo_states(&app).is_empty());
+        }
 
-            app.input = String::from("rad");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![3.141592653589793]); // ~π
+        #[test]
+        fn keep_top_on_empty_stack() {
+            let mut app = App::new();
+            app.perform_keep_top();
+            assert!(app.stack.is_empty());
+            assert!(undo_states(&app).is_empty());
         }
 
         #[test]
-        fn factorial() {
+        fn swap() {
             let mut app = App::new();
-            app.input = String::from("5");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![5.0]);
+            app.push_number(1.0);
+            app.push_number(2.0);
+            app.perform_swap();
+            assert_eq!(app.stack.pop().unwrap(), 1.0);
+            assert_eq!(app.stack.pop().unwrap(), 2.0);
+        }
+    }
 
-            app.input = String::from("!");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![120.0]);
+    mod display {
+
+        use super::{
+            changed_positions, decompose_bits, format_engineering, format_operation,
+            format_with_precision, histogram_bars, infix_step, page_offset, plot_bounds,
+            plot_points, sparkline, trig_preview, AngleMode, App, Arity,
+        };
+
+        /// Writes an RPN sequence in infix form, or `None` if a step can't be shown.
+        fn rpn_to_infix(tokens: &[&str]) -> Option<String> {
+            let mut expressions = Vec::new();
+            for token in tokens {
+                if !infix_step(&mut expressions, token) {
+                    return None;
+                }
+            }
+            expressions.pop().map(|(text, _)| text)
         }
 
         #[test]
-        fn reciprocal() {
-            let mut app = App::new();
-            app.input = String::from("4");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![4.0]);
+        fn sparkline_scaling() {
+            assert_eq!(sparkline(&[1.0, 2.0, 3.0, 2.0, 1.0]), "▁▅█▅▁");
+            assert_eq!(sparkline(&[-5.0, f64::NAN, 9.0]), "▁ █");
+            assert_eq!(sparkline(&[4.0, 4.0]), "▄▄");
+            assert_eq!(sparkline(&[]), "");
+        }
 
-            app.input = String::from("recip");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![0.25]);
+        #[test]
+        fn plot_dataset() {
+            let points = plot_points(&[1.0, 4.0, f64::INFINITY, -2.0]);
+            assert_eq!(points, vec![(0.0, 1.0), (1.0, 4.0), (3.0, -2.0)]);
+            assert_eq!(plot_bounds(&points), ([0.0, 3.0], [-2.0, 4.0]));
+
+            // A single value still gets a range to sit in
+            assert_eq!(plot_bounds(&plot_points(&[5.0])), ([-1.0, 1.0], [4.0, 6.0]));
+            assert_eq!(plot_bounds(&[]), ([0.0, 1.0], [0.0, 1.0]));
         }
 
         #[test]
-        fn log_base_10() {
+        fn histogram_scaling() {
+            assert_eq!(
+                histogram_bars(&[2.0, -4.0, 1.0, 0.0]),
+                vec![(50, false), (100, true), (25, false), (0, false)]
+            );
+            assert_eq!(
+                histogram_bars(&[0.0, f64::NAN, -3.0]),
+                vec![(0, false), (0, false), (100, true)]
+            );
+            assert_eq!(histogram_bars(&[0.0]), vec![(0, false)]);
+            assert!(histogram_bars(&[]).is_empty());
+        }
+
+        #[test]
+        fn bit_decomposition() {
+            assert_eq!(decompose_bits(1.0), (0, 1023, 0));
+            assert_eq!(decompose_bits(-2.5), (1, 1024, 1 << 50));
+            assert_eq!(decompose_bits(-0.0), (1, 0, 0));
+            assert_eq!(decompose_bits(f64::INFINITY), (0, 0x7ff, 0));
+
             let mut app = App::new();
-            app.input = String::from("100");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![100.0]);
+            app.stack = vec![1.0];
+            assert_eq!(
+                app.bits_text(),
+                "0x3ff0000000000000  sign 0  exponent 1023 (2^0)  mantissa 0x0000000000000"
+            );
+        }
 
-            app.input = String::from("log10");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![2.0]);
+        #[test]
+        fn rpn_to_infix_conversion() {
+            assert_eq!(
+                rpn_to_infix(&["2", "3", "+", "4", "*"]).as_deref(),
+                Some("(2 + 3) * 4")
+            );
+            assert_eq!(
+                rpn_to_infix(&["2", "3", "4", "*", "+"]).as_deref(),
+                Some("2 + 3 * 4")
+            );
+            assert_eq!(
+                rpn_to_infix(&["10", "2", "3", "-", "-"]).as_deref(),
+                Some("10 - (2 - 3)")
+            );
+            assert_eq!(rpn_to_infix(&["2", "3", "^"]).as_deref(), Some("3 ^ 2"));
+            assert_eq!(
+                rpn_to_infix(&["9", "sqrt", "pi", "*", "neg"]).as_deref(),
+                Some("-(sqrt(9) * pi)")
+            );
+            assert_eq!(rpn_to_infix(&["1", "2", "rolln"]), None);
         }
 
         #[test]
-        fn log_base_natural() {
+        fn infix_command() {
             let mut app = App::new();
-            app.input = String::from("2.718281828459045"); // e
+            app.stack = vec![7.0];
+            for input in ["2", "3", "+", "4", "*", "infix"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.status.as_deref(), Some("(2 + 3) * 4"));
+
+            app.input = String::from("+");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![2.718281828459045]);
+            app.input = String::from("infix");
+            app.process_input().unwrap();
+            assert_eq!(app.status.as_deref(), Some("7 + (2 + 3) * 4"));
 
-            app.input = String::from("logn");
+            // Undo can't be followed, so the values start over as plain numbers
+            app.input = String::from("undo");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![1.0]);
+            app.input = String::from("infix");
+            app.process_input().unwrap();
+            assert_eq!(app.status.as_deref(), Some("20"));
         }
 
         #[test]
-        fn log_base_2() {
+        fn trig_hint() {
+            assert_eq!(
+                trig_preview("sindeg", 90.0, AngleMode::Radians),
+                Some(("degrees", 1.0))
+            );
+            assert_eq!(
+                trig_preview("cos", 0.0, AngleMode::Radians),
+                Some(("radians", 1.0))
+            );
+            assert_eq!(
+                trig_preview("asin", 1.0, AngleMode::Degrees),
+                Some(("degrees", 90.0))
+            );
+            assert_eq!(trig_preview("sqrt", 4.0, AngleMode::Radians), None);
+
             let mut app = App::new();
-            app.input = String::from("8");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![8.0]);
+            app.input = String::from("sindeg");
+            assert_eq!(app.trig_hint(), None);
+            app.stack = vec![90.0];
+            assert_eq!(app.trig_hint().unwrap(), "sindeg(90) in degrees = 1");
+        }
 
-            app.input = String::from("log2");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![3.0]);
+        #[test]
+        fn engineering_notation() {
+            assert_eq!(format_engineering(1500.0), "1.5k");
+            assert_eq!(format_engineering(-0.000_047), "-47µ");
+            assert_eq!(format_engineering(2.2e6), "2.2M");
+            assert_eq!(format_engineering(999.999_999_999_99), "1k");
+            assert_eq!(format_engineering(12.0), "12");
+            assert_eq!(format_engineering(0.0), "0");
         }
 
         #[test]
-        fn swap() {
+        fn inline_summary() {
             let mut app = App::new();
-            app.input = String::from("10");
-            app.process_input().unwrap();
-            app.input = String::from("5");
-            app.process_input().unwrap();
+            assert_eq!(app.inline_summary(), "");
 
-            app.input = String::from("swap");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![5.0, 10.0]);
+            app.stack = vec![3.0, 2.0];
+            assert_eq!(app.inline_summary(), "3 | 2");
+
+            app.stack = vec![1.0, 5.0, 3.0, 2.5];
+            assert_eq!(app.inline_summary(), "… 5 | 3 | 2.5");
         }
 
         #[test]
-        fn clear() {
+        fn stack_entry_indices() {
             let mut app = App::new();
-            app.input = String::from("10");
-            app.process_input().unwrap();
-            app.input = String::from("5");
-            app.process_input().unwrap();
+            assert_eq!(app.format_stack_entry(2, 7.5), "2: 7.5");
 
-            app.input = String::from("clear");
+            app.input = String::from("index");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![]);
+            assert_eq!(app.format_stack_entry(2, 7.5), "7.5");
         }
 
         #[test]
-        fn drop() {
+        fn changed_entries() {
+            assert_eq!(changed_positions(&[1.0, 2.0, 3.0], &[1.0, 5.0]), vec![1]);
+            assert_eq!(changed_positions(&[1.0], &[1.0, 1.0]), vec![1]);
+            assert_eq!(changed_positions(&[1.0, 2.0], &[2.0, 1.0]), vec![0, 1]);
+            assert!(changed_positions(&[1.0, 2.0], &[1.0]).is_empty());
+            assert!(changed_positions(&[f64::NAN], &[f64::NAN]).is_empty());
+
             let mut app = App::new();
-            app.input = String::from("10");
-            app.process_input().unwrap();
-            app.input = String::from("5");
+            app.stack = vec![1.0, 2.0, 3.0];
+            app.input = String::from("+");
             app.process_input().unwrap();
+            assert_eq!(app.changed, vec![1]);
+        }
 
-            app.input = String::from("drop");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0]);
+        #[test]
+        fn page_scrolling() {
+            assert_eq!(page_offset(0, 10, 25, true), 10);
+            assert_eq!(page_offset(10, 10, 25, true), 15);
+            assert_eq!(page_offset(15, 10, 25, false), 5);
+            assert_eq!(page_offset(5, 10, 25, false), 0);
+            assert_eq!(page_offset(0, 10, 4, true), 0);
+            // An offset left over from a taller stack is clamped before moving
+            assert_eq!(page_offset(40, 10, 25, false), 5);
         }
 
         #[test]
-        fn undo_redo() {
+        fn result_text() {
             let mut app = App::new();
+            assert_eq!(app.result_text(), "");
 
-            // Push 3.0 to the stack
-            app.input = String::from("3");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![3.0]);
-
-            // Push 7.0 to the stack
-            app.input = String::from("7");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![3.0, 7.0]);
+            app.stack = vec![1.0, 42.5];
+            assert_eq!(app.result_text(), "= 42.5");
+        }
 
-            // Perform addition
-            app.input = String::from("+");
-            app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0]);
+        #[test]
+        fn operation_arity() {
+            assert_eq!(format_operation("+", Arity::Fixed(2)), "+ (2)");
+            assert_eq!(format_operation("sqrt", Arity::Fixed(1)), "sqrt (1)");
+            assert_eq!(format_operation("vadd", Arity::Counted), "vadd (n)");
+            assert_eq!(format_operation("undo", Arity::Fixed(0)), "undo");
+        }
 
-            // Undo the addition
-            app.input = String::from("undo");
+        #[test]
+        fn trailing_zeros() {
+            let mut app = App::new();
+            app.input = String::from("fix 3");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![3.0, 7.0]);
+            assert_eq!(app.format_value(4.1), "4.100");
+            assert_eq!(app.format_value(4.0), "4.000");
 
-            // Redo the addition
-            app.input = String::from("redo");
+            app.input = String::from("zeros");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![10.0]);
+            assert_eq!(app.format_value(4.1), "4.1");
+            assert_eq!(app.format_value(4.0), "4");
+            assert_eq!(app.format_value(400.0), "400");
         }
 
         #[test]
-        fn push_infinity() {
+        fn negative_zero() {
             let mut app = App::new();
-            app.input = String::from("inf");
+            assert_eq!(app.format_value(-0.0), "0");
+            assert_eq!(app.format_value(-0.5), "-0.5");
+
+            app.input = String::from("fix 2");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![f64::INFINITY])
+            assert_eq!(app.format_value(-0.001), "0.00");
+            assert_eq!(app.format_value(-0.01), "-0.01");
+
+            app.input = String::from("debugmode");
+            app.process_input().unwrap();
+            assert_eq!(app.format_value(-0.0), "-0.00");
         }
 
         #[test]
-        fn push_pi() {
+        fn invalid_precision() {
             let mut app = App::new();
-            app.input = String::from("pi");
+            app.input = String::from("fix -1");
             app.process_input().unwrap();
-            assert_eq!(app.stack, vec![f64::consts::PI])
+            assert_eq!(app.precision, None);
+            assert!(app.error.is_some());
         }
 
         #[test]
-        fn quit() {
-            let mut app = App::new();
-            app.input = String::from("quit");
-            assert!(!app.process_input().unwrap());
+        fn fixed_precision() {
+            assert_eq!(format_with_precision(1.23456, 2), "1.23");
+            assert_eq!(format_with_precision(2.0, 3), "2.000");
+            assert_eq!(format_with_precision(2.5, 0), "2");
         }
     }
 
-    mod function_tests {
-        use core::f64;
+    mod history {
+
+        use super::{format_history, parse_history, App, MAX_HISTORY};
 
-        use super::App;
         #[test]
-        fn push_number() {
+        fn recall_with_up_and_down() {
             let mut app = App::new();
-            app.push_number(5.6);
-            assert_eq!(app.stack.pop().unwrap(), 5.6);
+            for input in ["1", "2", "+"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            app.input = String::from("3");
+            app.character_index = 1;
+
+            app.recall_previous();
+            assert_eq!(app.input, "+");
+            assert_eq!(app.character_index, 1);
+            app.recall_previous();
+            app.recall_previous();
+            assert_eq!(app.input, "1");
+            // Up stops at the oldest entry
+            app.recall_previous();
+            assert_eq!(app.input, "1");
+
+            // Editing a recalled line leaves the history alone
+            app.enter_char('0');
+            assert_eq!(app.input, "10");
+            assert_eq!(app.history, vec!["1", "2", "+"]);
+
+            app.recall_next();
+            assert_eq!(app.input, "2");
+            app.recall_next();
+            app.recall_next();
+            assert_eq!(app.input, "3");
+            assert_eq!(app.character_index, 1);
+            app.recall_next();
+            assert_eq!(app.input, "3");
+
+            // Submitting starts recall again from the newest entry
+            app.recall_previous();
+            app.process_input().unwrap();
+            app.recall_previous();
+            assert_eq!(app.input, "+");
         }
 
         #[test]
-        fn addition() {
+        fn records_inputs() {
             let mut app = App::new();
-            app.push_number(5.0);
-            app.push_number(3.0);
-            app.perform_operation(|a, b| a + b);
-            assert_eq!(app.stack.pop().unwrap(), 8.0);
+            for input in ["1", "2", "2", "+", ""] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.history, vec!["1", "2", "+"]);
         }
 
         #[test]
-        fn subtraction() {
-            let mut app = App::new();
-            app.push_number(10.0);
-            app.push_number(4.0);
-            app.perform_operation(|a, b| a - b);
-            assert_eq!(app.stack.pop().unwrap(), 6.0);
+        fn history_round_trip() {
+            let history = vec![String::from("1"), String::from("convert m ft")];
+            assert_eq!(parse_history(&format_history(&history)), history);
         }
 
         #[test]
-        fn multiplication() {
+        fn history_is_capped() {
             let mut app = App::new();
-            app.push_number(2.0);
-            app.push_number(3.0);
-            app.perform_operation(|a, b| a * b);
-            assert_eq!(app.stack.pop().unwrap(), 6.0);
+            for i in 0..=MAX_HISTORY {
+                app.input = i.to_string();
+                app.process_input().unwrap();
+            }
+            assert_eq!(app.history.len(), MAX_HISTORY);
+            assert_eq!(app.history[0], "1");
+
+            let text: String = (0..MAX_HISTORY + 10).map(|i| format!("{i}\n")).collect();
+            let history = parse_history(&text);
+            assert_eq!(history.len(), MAX_HISTORY);
+            assert_eq!(history[0], "10");
         }
+    }
+
+    mod config {
+
+        use super::{AngleMode, App, Config, InputMode};
 
         #[test]
-        fn division() {
+        fn start_mode() {
             let mut app = App::new();
-            app.push_number(10.0);
-            app.push_number(2.0);
-            app.perform_operation(|a, b| a / b);
-            assert_eq!(app.stack.pop().unwrap(), 5.0);
+            app.apply_config(Config::parse("start_mode = \"normal\"").unwrap());
+            assert_eq!(app.input_mode, InputMode::Normal);
+            assert_eq!(app.config().start_mode, InputMode::Normal);
+
+            app.input_mode = InputMode::Editing;
+            assert_eq!(app.config().start_mode, InputMode::Normal);
+            assert_eq!(App::new().input_mode, InputMode::Editing);
         }
 
         #[test]
-        fn clone() {
+        fn parse_config() {
+            let config =
+                Config::parse("precision = 3\ntrailing_zeros = false\nresult = true\n").unwrap();
+            assert_eq!(
+                config,
+                Config {
+                    precision: Some(3),
+                    trailing_zeros: false,
+                    summary: false,
+                    result: true,
+                    indices: true,
+                    confirm: false,
+                    start_mode: InputMode::Editing,
+                    angle_mode: AngleMode::Radians,
+                    max_undo: 100,
+                }
+            );
+
             let mut app = App::new();
-            app.push_number(10.0);
-            app.push_number(2.0);
-            app.perform_clone();
-            assert_eq!(app.stack.pop().unwrap(), 2.0);
+            app.apply_config(config);
+            assert_eq!(app.precision, Some(3));
+            assert!(!app.trailing_zeros);
+            assert!(!app.show_summary);
+            assert!(app.show_result);
         }
 
         #[test]
-        fn modulo() {
+        fn config_round_trip() {
             let mut app = App::new();
-            app.push_number(17.0);
-            app.push_number(5.0);
-            app.perform_operation(|a, b| a % b);
-            assert_eq!(app.stack.pop().unwrap(), 2.0);
+            app.precision = Some(4);
+            app.trailing_zeros = false;
+            app.show_summary = true;
+
+            let saved = app.config().to_toml().unwrap();
+            let mut restored = App::new();
+            restored.apply_config(Config::parse(&saved).unwrap());
+            assert_eq!(restored.config(), app.config());
+
+            app.precision = None;
+            let saved = app.config().to_toml().unwrap();
+            assert_eq!(Config::parse(&saved).unwrap().precision, None);
         }
 
         #[test]
-        fn exponent() {
+        fn bad_config_falls_back_to_defaults() {
+            let path =
+                std::env::temp_dir().join(format!("rpn-calc-config-{}.toml", std::process::id()));
+            std::fs::write(&path, "precision = 3\nangel_mode = \"degrees\"\n").unwrap();
             let mut app = App::new();
-            app.push_number(4.0);
-            app.push_number(5.0);
-            app.perform_operation(|a, b| b.powf(a));
-            assert_eq!(app.stack.pop().unwrap(), 625.0);
+            app.load_config_from(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(app.config(), App::new().config());
+            assert!(app
+                .status
+                .as_deref()
+                .unwrap()
+                .contains("unknown field `angel_mode`"));
+
+            // No file at all is not an error
+            let mut app = App::new();
+            app.load_config_from(&path);
+            assert_eq!(app.status, None);
         }
 
         #[test]
-        fn neg() {
+        fn state_round_trip() {
+            let path = std::env::temp_dir()
+                .join(format!("rpn-calc-test-{}", std::process::id()))
+                .join("state.json");
             let mut app = App::new();
-            app.push_number(4.0);
-            app.perform_single_operand_operation(|a| -a);
-            assert_eq!(app.stack.pop().unwrap(), -4.0);
+            app.stack = vec![1.5, -2.0, f64::INFINITY];
+            app.variables.insert(String::from("x"), 7.0);
+            app.save_state_to(&path).unwrap();
+
+            let mut restored = App::new();
+            restored.load_state_from(&path);
+            assert_eq!(restored.stack, app.stack);
+            assert_eq!(restored.variables, app.variables);
+
+            // A corrupt or missing file starts over with an empty stack
+            std::fs::write(&path, "{ not json").unwrap();
+            restored.load_state_from(&path);
+            assert!(restored.stack.is_empty());
+            std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+            restored.stack = vec![1.0];
+            restored.load_state_from(&path);
+            assert!(restored.stack.is_empty());
         }
 
         #[test]
-        fn abs() {
+        fn legacy_stack_file() {
+            let dir = std::env::temp_dir().join(format!("rpn-calc-legacy-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let (path, legacy) = (dir.join("state.json"), dir.join("stack.txt"));
+            std::fs::write(&legacy, "1.5\n-2\n").unwrap();
+
             let mut app = App::new();
-            app.push_number(-4.0);
-            app.perform_single_operand_operation(|a| a.abs());
-            assert_eq!(app.stack.pop().unwrap(), 4.0);
+            app.load_state_or_legacy(&path, &legacy);
+            assert_eq!(app.stack, vec![1.5, -2.0]);
+
+            // Once there is a state file, the old one is ignored
+            app.stack = vec![3.0];
+            app.save_state_to(&path).unwrap();
+            let mut restored = App::new();
+            restored.load_state_or_legacy(&path, &legacy);
+            assert_eq!(restored.stack, vec![3.0]);
+            std::fs::remove_dir_all(&dir).unwrap();
         }
 
         #[test]
-        fn sqrt() {
+        fn debug_state() {
             let mut app = App::new();
-            app.push_number(9.0);
-            app.perform_single_operand_operation(|a| a.sqrt());
-            assert_eq!(app.stack.pop().unwrap(), 3.0);
+            for input in ["1", "2", "+", "undo", "sq = clone *"] {
+                app.input = String::from(input);
+                app.process_input().unwrap();
+            }
+            let state = toml::to_string(&app.debug_state()).unwrap();
+            for expected in [
+                "stack = [1.0, 2.0]",
+                "undo_depth = 2",
+                "redo_depth = 1",
+                "input_mode = \"editing\"",
+                "formulas = [\"sq\"]",
+                "[settings]",
+                "trailing_zeros = true",
+            ] {
+                assert!(
+                    state.contains(expected),
+                    "{expected} missing from:\n{state}"
+                );
+            }
         }
 
         #[test]
-        fn sin() {
-            let mut app = App::new();
-            app.push_number(9.0);
-            app.perform_single_operand_operation(|a| a.sin());
-            assert_eq!(app.stack.pop().unwrap(), 0.4121184852417566);
+        fn empty_config_uses_defaults() {
+            assert_eq!(Config::parse("").unwrap(), Config::default());
         }
 
         #[test]
-        fn cos() {
-            let mut app = App::new();
-            app.push_number(5.0);
-            app.perform_single_operand_operation(|a| a.cos());
-            assert_eq!(app.stack.pop().unwrap(), 0.28366218546322625);
+        fn unknown_setting_is_rejected() {
+            assert!(Config::parse("colour = \"red\"").is_err());
         }
+    }
+
+    mod batch {
+
+        use std::process::ExitCode;
+
+        use super::App;
 
         #[test]
-        fn tan() {
-            let mut app = App::new();
-            app.push_number(6.0);
-            app.perform_single_operand_operation(|a| a.tan());
-            assert_eq!(app.stack.pop().unwrap(), -0.29100619138474915);
+        fn script_succeeds() {
+            let script = "2\n3\n+\n\nprint\n5\nassert\n";
+            let result = App::new().run_batch(script.as_bytes()).unwrap();
+            assert_eq!(result, ExitCode::SUCCESS);
         }
 
         #[test]
-        fn asin() {
-            let mut app = App::new();
-            app.push_number(0.6);
-            app.perform_single_operand_operation(|a| a.asin());
-            assert_eq!(app.stack.pop().unwrap(), 0.6435011087932844);
+        fn failed_assertion_exits_non_zero() {
+            let script = "2\n3\n+\n6\nassert\n";
+            let result = App::new().run_batch(script.as_bytes()).unwrap();
+            assert_eq!(result, ExitCode::FAILURE);
         }
+    }
+
+    mod edge_cases {
+
+        use super::{
+            decimal_places, fold_states, formula_arity, is_known_command, levenshtein_distance,
+            round_to_significant_figures, stacks_approx_equal, suggest_command, App,
+        };
 
         #[test]
-        fn acos() {
-            let mut app = App::new();
-            app.push_number(0.7);
-            app.perform_single_operand_operation(|a| a.acos());
-            assert_eq!(app.stack.pop().unwrap(), 0.7953988301841436);
+        fn no_suggestion_for_short_tokens() {
+            assert_eq!(suggest_command("x"), None);
+            assert_eq!(suggest_command("f"), None);
+            assert_eq!(suggest_command("ab"), None);
+            assert_eq!(suggest_command("sqrtt"), Some("sqrt"));
         }
 
         #[test]
-        fn atan() {
-            let mut app = App::new();
-            app.push_number(5.0);
-            app.perform_single_operand_operation(|a| a.atan());
-            assert_eq!(app.stack.pop().unwrap(), 1.373400766945016);
+        fn script_commands_are_registered() {
+            assert!(is_known_command("assert"));
+            assert_eq!(suggest_command("asert"), Some("assert"));
+            assert_eq!(formula_arity("assert"), Some(2));
+
+            assert!(is_known_command("print"));
+            assert_eq!(suggest_command("prnt"), Some("print"));
+            assert_eq!(formula_arity("print sqrt"), Some(1));
         }
 
         #[test]
-        fn convert_to_degrees() {
+        fn formula_arities() {
+            assert_eq!(formula_arity("*"), Some(2));
+            assert_eq!(formula_arity("clone *"), Some(1));
+            assert_eq!(formula_arity("2 * pi *"), Some(1));
+            assert_eq!(formula_arity("pi 2 /"), Some(0));
+            assert_eq!(formula_arity("swap -"), Some(2));
+            assert_eq!(formula_arity("decimals +"), Some(1));
+            assert_eq!(formula_arity("rot +"), Some(3));
+            assert_eq!(formula_arity("over +"), Some(2));
+            assert_eq!(formula_arity("dupbottom +"), Some(1));
+            assert_eq!(formula_arity("vadd"), None);
+            assert_eq!(formula_arity("nonsense"), None);
+            assert_eq!(formula_arity(""), None);
+        }
+
+        #[test]
+        fn fold_intermediate_states() {
+            assert_eq!(
+                fold_states(&[10.0, 3.0, 2.0], |a, b| a - b).collect::<Vec<_>>(),
+                vec![vec![10.0, 3.0, 2.0], vec![7.0, 2.0], vec![5.0]]
+            );
+            assert_eq!(
+                fold_states(&[4.0], |a, b| a * b).collect::<Vec<_>>(),
+                vec![vec![4.0]]
+            );
+            assert_eq!(
+                fold_states(&[], |a, b| a + b).collect::<Vec<_>>(),
+                vec![Vec::<f64>::new()]
+            );
+        }
+
+        #[test]
+        fn tolerant_stack_equality() {
+            assert!(stacks_approx_equal(&[0.1 + 0.2, 1e12], &[0.3, 1e12 + 1e-3]));
+            assert!(!stacks_approx_equal(&[1.0, 2.0], &[1.0, 2.001]));
+            assert!(!stacks_approx_equal(&[1.0], &[1.0, 1.0]));
+            assert!(stacks_approx_equal(&[], &[]));
+        }
+
+        #[test]
+        fn significant_figures_of_small_and_negative_numbers() {
+            assert_eq!(round_to_significant_figures(0.0012345, 3.0), 0.00123);
+            assert_eq!(round_to_significant_figures(-98765.0, 1.0), -100000.0);
+            assert_eq!(round_to_significant_figures(0.0, 3.0), 0.0);
+        }
+
+        #[test]
+        fn significant_figures_beyond_precision() {
+            assert_eq!(round_to_significant_figures(1.5, 400.0), 1.5);
+            assert_eq!(round_to_significant_figures(1.5, 17.0), 1.5);
+            assert_eq!(round_to_significant_figures(5e-324, 1.0), 5e-324);
+            assert_eq!(round_to_significant_figures(2.5e-320, 2.0), 2.5e-320);
+        }
+
+        #[test]
+        fn descending_range() {
             let mut app = App::new();
+            app.push_number(3.0);
             app.push_number(1.0);
-            app.perform_single_operand_operation(|a| a.to_degrees());
-            assert_eq!(app.stack.pop().unwrap(), 57.29577951308232);
+            app.perform_range();
+            assert_eq!(app.stack, vec![3.0, 2.0, 1.0]);
         }
 
         #[test]
-        fn convert_to_radians() {
+        fn over_budget_range_is_rejected() {
             let mut app = App::new();
-            app.push_number(95.0);
-            app.perform_single_operand_operation(|a| a.to_radians());
-            assert_eq!(app.stack.pop().unwrap(), 1.6580627893946132);
+            app.push_number(0.0);
+            app.push_number(1e9);
+            app.perform_range();
+            assert_eq!(app.stack, vec![0.0, 1e9]);
+            assert!(app.error.is_some());
         }
 
         #[test]
-        fn factorial() {
-            let mut app = App::new();
-            app.push_number(5.0);
-            app.perform_factorial();
-            assert_eq!(app.stack.pop().unwrap(), 120.0);
+        fn decimal_places_of_whole_and_tiny_numbers() {
+            assert_eq!(decimal_places(42.0), 0);
+            assert_eq!(decimal_places(0.0000001), 7);
+            assert_eq!(decimal_places(f64::NAN), 0);
         }
 
         #[test]
-        fn recipricol() {
+        fn vector_operation_with_too_few_values() {
             let mut app = App::new();
-            app.push_number(4.0);
-            app.perform_single_operand_operation(|a| 1.0 / a);
-            assert_eq!(app.stack.pop().unwrap(), 0.25);
+            app.push_number(1.0);
+            app.push_number(2.0);
+            app.push_number(3.0);
+            app.push_number(2.0);
+            app.perform_vector_operation(|a, b| a + b);
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0, 2.0]);
+            assert_eq!(app.error.as_deref(), Some("need 5 values, have 4"));
         }
 
         #[test]
-        fn log10() {
+        fn vector_operation_with_fractional_length() {
             let mut app = App::new();
-            app.push_number(50.0);
-            app.perform_single_operand_operation(|a| a.log(10.0));
-            assert_eq!(app.stack.pop().unwrap(), 1.6989700043360185);
+            app.push_number(1.0);
+            app.push_number(2.0);
+            app.push_number(0.5);
+            app.perform_vector_operation(|a, b| a + b);
+            assert_eq!(app.stack, vec![1.0, 2.0, 0.5]);
+            assert!(app.error.is_some());
         }
 
         #[test]
-        fn logn() {
+        fn cross_product_with_too_few_values() {
             let mut app = App::new();
-            app.push_number(50.0);
-            app.perform_single_operand_operation(|a| a.ln());
-            assert_eq!(app.stack.pop().unwrap(), 3.912023005428146);
+            for value in [1.0, 2.0, 3.0, 4.0, 5.0] {
+                app.push_number(value);
+            }
+            app.perform_cross_product();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+            assert_eq!(app.error.as_deref(), Some("need 6 values, have 5"));
         }
 
         #[test]
-        fn log2() {
+        fn norm_leaves_values_below_the_vector() {
             let mut app = App::new();
-            app.push_number(50.0);
-            app.perform_single_operand_operation(|a| a.log(2.0));
-            assert_eq!(app.stack.pop().unwrap(), 5.643856189774724);
+            for value in [7.0, 1.0, 2.0, 2.0, 3.0] {
+                app.push_number(value);
+            }
+            app.perform_norm();
+            assert_eq!(app.stack, vec![7.0, 3.0]);
         }
 
         #[test]
-        fn push_infinity() {
+        fn assert_tolerates_rounding() {
             let mut app = App::new();
-            app.push_infinity();
-            assert_eq!(app.stack.pop().unwrap(), f64::INFINITY)
+            app.push_number(0.1 + 0.2);
+            app.push_number(0.3);
+            app.perform_assert();
+            assert_eq!(app.error, None);
         }
 
         #[test]
-        fn push_pi() {
+        fn swap_pairs_with_too_few_values() {
             let mut app = App::new();
-            app.push_pi();
-            assert_eq!(app.stack.pop().unwrap(), f64::consts::PI)
+            app.push_number(1.0);
+            app.push_number(2.0);
+            app.push_number(3.0);
+            app.perform_swap2();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
+            assert_eq!(app.error.as_deref(), Some("need 4 values, have 3"));
         }
 
         #[test]
-        fn undo_redo() {
+        fn roll_n_out_of_range() {
             let mut app = App::new();
+            app.push_number(1.0);
+            app.push_number(2.0);
             app.push_number(3.0);
-            app.push_number(7.0);
-            app.perform_operation(|a, b| a + b);
-
-            // Verify that the stack has the result of the addition
-            assert_eq!(app.stack, vec![10.0]);
-
-            // Undo the addition, should revert to the original stack state
-            app.undo();
-            assert_eq!(app.stack, vec![3.0, 7.0]);
+            app.perform_roll_n();
+            assert_eq!(app.stack, vec![1.0, 2.0, 3.0]);
+            assert_eq!(app.error.as_deref(), Some("need 4 values, have 3"));
+        }
 
-            // Redo the addition, should return the stack to [10.0]
-            app.redo();
-            assert_eq!(app.stack, vec![10.0]);
+        #[test]
+        fn edit_distance() {
+            assert_eq!(levenshtein_distance("suqrt", "sqrt"), 1);
+            assert_eq!(levenshtein_distance("kitten", "sitting"), 3);
+            assert_eq!(levenshtein_distance("", "abc"), 3);
+            assert_eq!(levenshtein_distance("swap", "swap"), 0);
         }
 
         #[test]
-        fn clear() {
+        fn factorial_overflow() {
             let mut app = App::new();
-            app.push_number(42.0);
-            app.perform_clear();
-            assert!(app.stack.is_empty());
+            app.push_number(20.0);
+            app.perform_factorial();
+            assert_eq!(app.stack.pop().unwrap(), 2_432_902_008_176_640_000.0);
+
+            app.push_number(21.0);
+            app.perform_factorial();
+            assert_eq!(app.stack.pop().unwrap(), 51_090_942_171_709_440_000.0);
+
+            app.push_number(171.0);
+            app.perform_factorial();
+            assert_eq!(app.stack.pop().unwrap(), f64::INFINITY);
+
+            // Huge operands go straight to infinity instead of looping
+            app.push_number(1e18);
+            app.perform_factorial();
+            assert_eq!(app.stack.pop().unwrap(), f64::INFINITY);
         }
 
         #[test]
-        fn drop() {
+        fn factorial_drops_sign_of_whole_numbers() {
             let mut app = App::new();
-            app.push_number(5.0);
-            app.push_number(10.0);
-            app.perform_drop();
-            assert_eq!(app.stack.len(), 1);
-            assert_eq!(app.stack.pop().unwrap(), 5.0);
+            app.push_number(-3.0);
+            app.perform_factorial();
+            assert_eq!(app.stack, vec![6.0]);
         }
 
         #[test]
-        fn swap() {
+        fn gamma_factorial() {
             let mut app = App::new();
-            app.push_number(1.0);
-            app.push_number(2.0);
-            app.perform_swap();
-            assert_eq!(app.stack.pop().unwrap(), 1.0);
-            assert_eq!(app.stack.pop().unwrap(), 2.0);
-        }
-    }
+            app.push_number(0.5);
+            app.perform_factorial();
+            assert!((app.stack.pop().unwrap() - 0.886_226_925_452_758).abs() < 1e-12);
 
-    mod edge_cases {
+            app.push_number(-0.5);
+            app.perform_factorial();
+            assert!((app.stack.pop().unwrap() - 1.772_453_850_905_516).abs() < 1e-12);
 
-        use super::App;
+            app.push_number(5.0);
+            app.perform_factorial();
+            assert_eq!(app.stack.pop().unwrap(), 120.0);
+
+            app.push_number(170.5);
+            app.perform_factorial();
+            assert!(app.stack.pop().unwrap().is_finite());
+        }
 
         #[test]
         fn divide_pos_by_0() {